@@ -0,0 +1,291 @@
+use std::path::Path;
+
+use ramhorns::Content;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::date::validate_format;
+use crate::math::MathMode;
+use crate::post::validate_permalink;
+use crate::sanitise::RawHtml;
+use crate::utils::SlugMode;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Error reading site config")]
+    FileError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("Invalid site config. {source}")]
+    InvalidToml {
+        #[from]
+        source: toml::de::Error,
+    },
+    #[error("Invalid site config. `{key}` {reason}")]
+    InvalidValue { key: &'static str, reason: String },
+}
+
+/// What templates are rendered with: the page's own data plus the site
+/// config.
+#[derive(Content)]
+pub struct Page<'a, T>
+where
+    T: Content,
+{
+    #[ramhorns(flatten)]
+    page: &'a T,
+    site: &'a SiteConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub site: SiteConfig,
+    pub index: IndexConfig,
+    pub markdown: MarkdownConfig,
+    pub toc: TocConfig,
+    pub feed: FeedConfig,
+}
+
+/// Exposed to every template as `site`, e.g. `{{site title}}` or
+/// `{{#site}}{{description}}{{/site}}`.
+#[derive(Deserialize, Content, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    pub title: String,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    /// Where the built site is hosted, e.g. `https://example.com/blog`.
+    /// Needed for anything that must contain absolute URLs.
+    pub base_url: Option<String>,
+    /// How heading ids, tag pages and post slugs are written
+    #[ramhorns(skip)]
+    pub slugs: SlugMode,
+    /// Where posts are served from, e.g. `/:year/:month/:slug/`. Patterns
+    /// can use `:year`, `:month`, `:day`, `:slug` and `:path`, the source
+    /// path without its extension. Posts can override this with `permalink`
+    /// in their front matter.
+    #[ramhorns(skip)]
+    pub permalink: String,
+    /// How posts' `date.formatted` is written, in `strftime` format
+    #[ramhorns(skip)]
+    pub date_format: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+    pub page_size: usize,
+    /// Roughly how many characters of a post to use as its excerpt, when it
+    /// has neither an `excerpt` in its front matter nor a `<!-- more -->`
+    pub excerpt_length: usize,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownConfig {
+    pub math: MathMode,
+    /// What to do with raw HTML in posts. Posts can override this with
+    /// `raw_html` in their front matter.
+    pub raw_html: RawHtml,
+    /// Render footnotes into the `footnotes` template variable instead of at
+    /// the end of `content`
+    pub separate_footnotes: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TocConfig {
+    /// The shallowest heading level listed. Shallower headings are left out
+    /// and their subsections take their place.
+    pub min_depth: u8,
+    /// The deepest heading level listed
+    pub max_depth: u8,
+    /// Number entries `1`, `1.1`, `1.2`...
+    pub numbered: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedConfig {
+    /// Write an Atom feed to `feed.xml`
+    pub atom: bool,
+    /// Write an RSS 2.0 feed to `rss.xml`
+    pub rss: bool,
+    pub content: FeedContent,
+    /// The maximum number of posts in each feed
+    pub limit: usize,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedContent {
+    #[default]
+    Full,
+    Excerpt,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        SiteConfig {
+            title: String::new(),
+            description: None,
+            author: None,
+            base_url: None,
+            slugs: SlugMode::default(),
+            permalink: "/:path.html".to_owned(),
+            date_format: "%B %-d, %Y".to_owned(),
+        }
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            page_size: 20,
+            excerpt_length: 300,
+        }
+    }
+}
+
+impl Default for TocConfig {
+    fn default() -> Self {
+        TocConfig {
+            min_depth: 2,
+            max_depth: 6,
+            numbered: false,
+        }
+    }
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        FeedConfig {
+            atom: true,
+            rss: false,
+            content: FeedContent::Full,
+            limit: 20,
+        }
+    }
+}
+
+impl Config {
+    /// Loads `_config/site.toml`, falling back to the defaults if the site
+    /// doesn't have one.
+    pub fn from_site_root(site_root: &Path) -> Result<Config, ConfigError> {
+        let config_path = site_root.join("_config/site.toml");
+        match std::fs::read_to_string(config_path) {
+            Ok(source) => Config::from_toml(&source),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Wraps a post or index for rendering, so templates can use `site`.
+    pub fn page<'a, T: Content>(&'a self, page: &'a T) -> Page<'a, T> {
+        Page {
+            page,
+            site: &self.site,
+        }
+    }
+
+    /// The absolute URL of `path` (which should start with a `/`) on the
+    /// hosted site, if `site.base_url` is set.
+    pub fn absolute_url(&self, path: &str) -> Option<String> {
+        let base_url = self.site.base_url.as_deref()?;
+        Some(format!("{}{path}", base_url.trim_end_matches('/')))
+    }
+
+    pub fn from_toml(source: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(source)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.index.page_size == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "index.page_size",
+                reason: "must be at least 1".to_owned(),
+            });
+        }
+        if !(2..=6).contains(&self.toc.min_depth) {
+            return Err(ConfigError::InvalidValue {
+                key: "toc.min_depth",
+                reason: "must be between 2 and 6".to_owned(),
+            });
+        }
+        if !(self.toc.min_depth..=6).contains(&self.toc.max_depth) {
+            return Err(ConfigError::InvalidValue {
+                key: "toc.max_depth",
+                reason: "must be between `toc.min_depth` and 6".to_owned(),
+            });
+        }
+        if self.feed.limit == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "feed.limit",
+                reason: "must be at least 1".to_owned(),
+            });
+        }
+        if let Err(reason) = validate_permalink(&self.site.permalink) {
+            return Err(ConfigError::InvalidValue {
+                key: "site.permalink",
+                reason,
+            });
+        }
+        if let Err(reason) = validate_format(&self.site.date_format) {
+            return Err(ConfigError::InvalidValue {
+                key: "site.date_format",
+                reason,
+            });
+        }
+        if let Some(base_url) = &self.site.base_url {
+            if !(base_url.starts_with("https://") || base_url.starts_with("http://")) {
+                return Err(ConfigError::InvalidValue {
+                    key: "site.base_url",
+                    reason: format!("must be an absolute http(s) URL, not {base_url:?}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ramhorns::Template;
+
+    #[test]
+    fn test_page() {
+        #[derive(Content)]
+        struct Post {
+            title: &'static str,
+        }
+
+        let config = Config::from_toml(
+            "[site]\ntitle = \"My <Site>\"\ndescription = \"Notes\"\nbase_url = \"https://example.com\"",
+        )
+        .unwrap();
+        let template = Template::new(
+            "{{title}} | {{site title}} | {{#site}}{{description}} {{base_url}}{{/site}}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&config.page(&Post { title: "Hello" })),
+            "Hello | My &lt;Site&gt; | Notes https://example.com"
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let err = Config::from_toml("[site]\ntitel = \"x\"").unwrap_err();
+        assert!(err.to_string().contains("unknown field `titel`"));
+        let err = Config::from_toml("[site]\nbase_url = \"example.com\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid site config. `site.base_url` must be an absolute http(s) URL, not \"example.com\""
+        );
+    }
+}
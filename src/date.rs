@@ -0,0 +1,108 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use ramhorns::Content;
+
+/// The formats front matter dates may be written in, besides RFC 3339.
+/// Dates without a timezone are taken to be UTC.
+const DATETIME_FORMATS: [&str; 6] = [
+    "%Y-%m-%d %H:%M:%S %z",
+    "%Y-%m-%d %H:%M %z",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+/// A post's date, with the forms templates are likely to want.
+#[derive(Content, Debug, Clone)]
+pub struct PostDate {
+    /// ISO 8601, e.g. `2023-08-01T09:30:00+01:00`
+    pub iso: String,
+    /// RFC 2822, as used by RSS and email, e.g. `Tue, 1 Aug 2023 09:30:00 +0100`
+    pub rfc2822: String,
+    /// Formatted with `site.date_format`, e.g. `August 1, 2023`
+    pub formatted: String,
+    #[ramhorns(skip)]
+    pub datetime: DateTime<FixedOffset>,
+}
+
+impl PostDate {
+    pub fn new(datetime: DateTime<FixedOffset>, format: &str) -> PostDate {
+        PostDate {
+            iso: datetime.to_rfc3339(),
+            rfc2822: datetime.to_rfc2822(),
+            formatted: datetime.format(format).to_string(),
+            datetime,
+        }
+    }
+}
+
+/// Parses a date from front matter or a filename: a day such as
+/// `2023-08-01`, optionally followed by a time and timezone.
+pub fn parse_date(text: &str) -> Option<DateTime<FixedOffset>> {
+    let text = text.trim();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(text) {
+        return Some(datetime);
+    }
+    for format in DATETIME_FORMATS {
+        if let Ok(datetime) = DateTime::parse_from_str(text, format) {
+            return Some(datetime);
+        }
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(text, format) {
+            return Some(Utc.from_utc_datetime(&datetime).fixed_offset());
+        }
+    }
+    let day = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(
+        Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0)?)
+            .fixed_offset(),
+    )
+}
+
+/// Checks a `strftime`-style format, which chrono would otherwise panic on
+/// while formatting.
+pub fn validate_format(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        Err(format!("isn't a valid date format: {format:?}"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let iso = |text| parse_date(text).map(|date| date.to_rfc3339());
+        assert_eq!(iso("2023-08-01").unwrap(), "2023-08-01T00:00:00+00:00");
+        assert_eq!(
+            iso("2023-08-01T09:30:00+01:00").unwrap(),
+            "2023-08-01T09:30:00+01:00"
+        );
+        assert_eq!(
+            iso("2023-08-01 09:30 +0100").unwrap(),
+            "2023-08-01T09:30:00+01:00"
+        );
+        assert_eq!(
+            iso("2023-08-01 09:30:00").unwrap(),
+            "2023-08-01T09:30:00+00:00"
+        );
+        assert_eq!(
+            iso("2023-08-01 09:30").unwrap(),
+            "2023-08-01T09:30:00+00:00"
+        );
+        assert_eq!(
+            iso("2023-08-01T09:30").unwrap(),
+            "2023-08-01T09:30:00+00:00"
+        );
+        assert_eq!(iso("2023-13-01"), None);
+        assert_eq!(iso("August"), None);
+
+        let date = PostDate::new(parse_date("2023-08-01").unwrap(), "%B %-d, %Y");
+        assert_eq!(date.formatted, "August 1, 2023");
+        assert_eq!(date.rfc2822, "Tue, 1 Aug 2023 00:00:00 +0000");
+        assert!(validate_format("%Y-%Q").is_err());
+    }
+}
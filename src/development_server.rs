@@ -2,15 +2,24 @@ use std::convert::Infallible;
 use std::ffi::OsStr;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server};
-use log::info;
+use log::{error, info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use percent_encoding::percent_decode_str;
 use ramhorns::{Content, Template};
 use syntect::parsing::SyntaxSet;
+use tokio::sync::broadcast::{self, error::RecvError};
 
-use crate::index::Index;
+use crate::config::Config;
+use crate::feed::{self, FeedError};
 use crate::post::{ParseError, Post};
+use crate::site::Site;
+use crate::utils;
 
 impl From<ParseError> for Response<String> {
     fn from(value: ParseError) -> Self {
@@ -27,39 +36,68 @@ impl From<ParseError> for Response<String> {
     }
 }
 
-fn template_from_path(path: &Path) -> Result<Template, ParseError> {
+fn template_from_path(path: &Path) -> Result<Template<'static>, ParseError> {
     Ok(Template::new(std::fs::read_to_string(path)?)?)
 }
 
 fn render_template_to_string<C: Content>(
     template: &Template,
     content: &C,
+    config: &Config,
 ) -> Result<String, ParseError> {
     let mut buf = Vec::<u8>::new();
-    match template.render_to_writer(&mut buf, &content) {
+    match template.render_to_writer(&mut buf, &config.page(content)) {
         Ok(()) => String::from_utf8(buf).map_err(|_| ParseError::InternalError),
         Err(err) => Err(err.into()),
     }
 }
 
-fn render_page(state: &State, uri_path: &str) -> Response<String> {
-    let template = match template_from_path(&state.page_template) {
-        Ok(template) => template,
-        Err(err) => return err.into(),
+/// Finds and parses the post served from `uri_path`. Posts are usually
+/// served from where their source file is, so that's tried before searching
+/// every post for the permalink.
+fn find_post(state: &State, uri_path: &str) -> Result<Post, ParseError> {
+    let syntax_set = state.syntax_set.read().unwrap();
+    let config = state.config.read().unwrap();
+    let directory = uri_path.strip_suffix("index.html").unwrap_or(uri_path);
+    let is_served_from = |post: &Post| {
+        let permalink = post.metadata.permalink.trim_start_matches('/');
+        permalink == uri_path
+            || permalink == directory
+            || permalink.strip_suffix('/') == Some(uri_path)
     };
 
-    let md_file = {
-        let mut md_file = std::path::Path::new(&state.site_root).join(uri_path);
-
-        if md_file.extension().and_then(OsStr::to_str) == Some("html") {
-            md_file.set_extension("md");
+    let mut md_file = PathBuf::from(uri_path);
+    if md_file.extension().and_then(OsStr::to_str) == Some("html") {
+        md_file.set_extension("md");
+    }
+    if utils::is_content(&state.site_root, &md_file) && state.site_root.join(&md_file).is_file() {
+        let post = Post::from_file(&state.site_root, &md_file, &syntax_set, &config)?;
+        if is_served_from(&post) {
+            return Ok(post);
         }
+    }
 
-        md_file
-    };
+    utils::files_within(&state.site_root, None)?
+        .iter()
+        .filter(|path| **path != md_file)
+        .filter_map(|path| Post::from_file(&state.site_root, path, &syntax_set, &config).ok())
+        .find(is_served_from)
+        .ok_or_else(|| ParseError::NotFound {
+            file: uri_path.to_owned(),
+        })
+}
 
-    Post::from_file(&state.site_root, &md_file, &state.syntax_set)
-        .and_then(|post| render_template_to_string(&template, &post))
+fn render_page(state: &State, uri_path: &str) -> Response<String> {
+    find_post(state, uri_path)
+        .and_then(|post| {
+            let template = match &post.metadata.layout {
+                Some(layout) => {
+                    template_from_path(&state.layout_dir.join(layout).with_extension("hbs"))?
+                }
+                None => template_from_path(&state.page_template)?,
+            };
+            render_template_to_string(&template, &post, &state.config.read().unwrap())
+        })
         .and_then(|page| {
             Response::builder()
                 .status(hyper::StatusCode::OK)
@@ -69,49 +107,217 @@ fn render_page(state: &State, uri_path: &str) -> Response<String> {
         .unwrap_or_else(Into::into)
 }
 
-fn render_index(state: &State) -> Response<String> {
-    let template = match template_from_path(&state.index_template) {
-        Ok(template) => template,
-        Err(err) => return err.into(),
+fn render_feed(
+    state: &State,
+    render: fn(&[Post], &Config) -> Result<String, FeedError>,
+    content_type: &'static str,
+) -> Response<Body> {
+    let syntax_set = state.syntax_set.read().unwrap();
+    let config = state.config.read().unwrap();
+    let site = match Site::from_path(&state.site_root, &syntax_set, &config) {
+        Ok(site) => site,
+        Err(err) => return html_response(ParseError::from(err).into()),
     };
 
-    let content = Index::from_path(&state.site_root, &state.syntax_set);
-
-    match content {
+    match render(site.posts(), &config) {
+        Ok(feed) => Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(feed))
+            .unwrap(),
         Err(err) => Response::builder()
-            .status(hyper::StatusCode::NOT_FOUND)
-            .body(format!(
-                "Error gathering posts for index from \"{}\": {}",
-                state.site_root.to_string_lossy(),
-                err
-            ))
-            .unwrap_or_else(|_| ParseError::InternalError.into()),
-        Ok(content) => render_template_to_string(&template, &content)
-            .and_then(|page| {
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+    }
+}
+
+/// Renders the listing page served from `permalink`, if there is one.
+fn render_index(state: &State, permalink: &str) -> Option<Response<String>> {
+    let config = state.config.read().unwrap();
+    let content = Site::from_path(&state.site_root, &state.syntax_set.read().unwrap(), &config)
+        .map(|site| site.indexes(&config));
+
+    let pages = match content {
+        Err(err) => {
+            return Some(
                 Response::builder()
-                    .status(hyper::StatusCode::OK)
-                    .body(page)
-                    .map_err(|_| ParseError::InternalError)
-            })
-            .unwrap_or_else(Into::into),
+                    .status(hyper::StatusCode::NOT_FOUND)
+                    .body(format!(
+                        "Error gathering posts for index from \"{}\": {}",
+                        state.site_root.to_string_lossy(),
+                        err
+                    ))
+                    .unwrap_or_else(|_| ParseError::InternalError.into()),
+            )
+        }
+        Ok(pages) => pages,
+    };
+    let content = pages.iter().find(|index| index.permalink() == permalink)?;
+
+    let template_path = match content.layout() {
+        "index" => state.index_template.clone(),
+        layout => state.layout_dir.join(layout).with_extension("hbs"),
+    };
+    let response = template_from_path(&template_path)
+        .and_then(|template| render_template_to_string(&template, content, &config))
+        .and_then(|page| {
+            Response::builder()
+                .status(hyper::StatusCode::OK)
+                .body(page)
+                .map_err(|_| ParseError::InternalError)
+        })
+        .unwrap_or_else(Into::into);
+    Some(response)
+}
+
+fn serve_asset(state: &State, uri_path: &Path) -> Option<Response<Body>> {
+    let contents = std::fs::read(state.site_root.join(uri_path)).ok()?;
+    let mime = mime_guess::from_path(uri_path).first_or_octet_stream();
+    Some(
+        Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(CONTENT_TYPE, mime.as_ref())
+            .body(Body::from(contents))
+            .unwrap(),
+    )
+}
+
+const LIVE_RELOAD_PATH: &str = "__rhodium/live-reload";
+const LIVE_RELOAD_SCRIPT: &str = "<script>new EventSource(\"/__rhodium/live-reload\")\
+    .onmessage = () => location.reload();</script>";
+
+/// Streams a server-sent event to the browser whenever something under the
+/// site root changes.
+fn live_reload_events(state: &State) -> Response<Body> {
+    let mut changes = state.changes.subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(()) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+            // Editors tend to touch a file several times per save
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            while changes.try_recv().is_ok() {}
+            if sender.send_data("data: reload\n\n".into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+/// Keeps the development server's cached syntaxes and config in step with
+/// the files on disk, and notifies open pages so they can reload.
+fn watch_site(state: Arc<State>) -> notify::Result<RecommendedWatcher> {
+    let watched_state = state.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let state = &watched_state;
+        let event = match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => event,
+            Ok(_) => return,
+            Err(err) => {
+                warn!("Error watching site for changes. {err}");
+                return;
+            }
+        };
+
+        let config_dir = state.site_root.join("_config");
+        if event
+            .paths
+            .iter()
+            .any(|p| p.starts_with(config_dir.join("syntaxes")))
+        {
+            match crate::load_syntax_set(&state.site_root) {
+                Ok(syntax_set) => *state.syntax_set.write().unwrap() = syntax_set,
+                Err(err) => error!("Couldn't reload syntaxes. {err}"),
+            }
+        }
+        if event
+            .paths
+            .iter()
+            .any(|p| p == &config_dir.join("site.toml"))
+        {
+            match Config::from_site_root(&state.site_root) {
+                Ok(config) => *state.config.write().unwrap() = config,
+                Err(err) => error!("Couldn't reload site config. {err}"),
+            }
+        }
+        // Nobody listening just means no pages are open
+        let _ = state.changes.send(());
+    })?;
+    watcher.watch(&state.site_root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+fn html_response(response: Response<String>) -> Response<Body> {
+    let (mut parts, mut body) = response.into_parts();
+    match body.rfind("</body>") {
+        Some(end_of_body) => body.insert_str(end_of_body, LIVE_RELOAD_SCRIPT),
+        None => body.push_str(LIVE_RELOAD_SCRIPT),
     }
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Response::from_parts(parts, Body::from(body))
 }
 
-async fn build_for_web<'a>(req: Request<Body>, state: &State) -> Response<String> {
+async fn build_for_web(req: Request<Body>, state: &State) -> Response<Body> {
     if req.method() != Method::GET {
-        return Response::builder()
-            .status(hyper::StatusCode::METHOD_NOT_ALLOWED)
-            .body("<h1>Method Not Allowed</1>".to_owned())
-            .unwrap();
+        return html_response(
+            Response::builder()
+                .status(hyper::StatusCode::METHOD_NOT_ALLOWED)
+                .body("<h1>Method Not Allowed</1>".to_owned())
+                .unwrap(),
+        );
     }
 
-    let uri_path = &req.uri().path()[1..];
+    // Decoded so `/img/my%20cat.png` finds `img/my cat.png`. Decoded `..`
+    // segments are still turned away by `utils::is_asset`.
+    let Ok(uri_path) = percent_decode_str(&req.uri().path()[1..]).decode_utf8() else {
+        return html_response(
+            Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body("<h1>Bad Request</h1>".to_owned())
+                .unwrap(),
+        );
+    };
+    let uri_path = uri_path.as_ref();
+
+    if uri_path == LIVE_RELOAD_PATH {
+        return live_reload_events(state);
+    }
+
+    // Listings are only ever served from directory URLs
+    let directory = uri_path.strip_suffix("index.html").unwrap_or(uri_path);
+    if directory.is_empty() || directory.ends_with('/') {
+        if let Some(response) = render_index(state, &format!("/{directory}")) {
+            return html_response(response);
+        }
+    }
 
-    if uri_path.is_empty() || uri_path == "index.html" {
-        render_index(state)
-    } else {
-        render_page(state, uri_path)
+    match uri_path {
+        "feed.xml" => return render_feed(state, feed::atom, "application/atom+xml"),
+        "rss.xml" => return render_feed(state, feed::rss, "application/rss+xml"),
+        _ => {}
     }
+
+    let asset_path = Path::new(uri_path);
+    if utils::is_asset(&state.site_root, asset_path) {
+        if let Some(response) = serve_asset(state, asset_path) {
+            return response;
+        }
+    }
+
+    html_response(render_page(state, uri_path))
 }
 
 async fn shutdown_signal() {
@@ -123,18 +329,27 @@ async fn shutdown_signal() {
 pub async fn serve_forever(
     site_root: PathBuf,
     syntax_set: SyntaxSet,
+    config: Config,
 ) -> std::result::Result<(), hyper::Error> {
     let state = {
-        let page_template = std::path::Path::new(&site_root).join("_config/layouts/post.hbs");
-        let index_template = std::path::Path::new(&site_root).join("_config/layouts/index.hbs");
-        std::sync::Arc::new(State {
+        let layout_dir = std::path::Path::new(&site_root).join("_config/layouts");
+        let page_template = layout_dir.join("post.hbs");
+        let index_template = layout_dir.join("index.hbs");
+        Arc::new(State {
             site_root,
+            layout_dir,
             page_template,
             index_template,
-            syntax_set,
+            syntax_set: RwLock::new(syntax_set),
+            config: RwLock::new(config),
+            changes: broadcast::channel(16).0,
         })
     };
 
+    let _watcher = watch_site(state.clone())
+        .map_err(|err| warn!("Live reload is disabled. Couldn't watch site for changes. {err}"))
+        .ok();
+
     let make_service = make_service_fn(|_| {
         let state = state.clone();
 
@@ -156,7 +371,10 @@ pub async fn serve_forever(
 
 struct State {
     site_root: PathBuf,
+    layout_dir: PathBuf,
     page_template: PathBuf,
     index_template: PathBuf,
-    syntax_set: SyntaxSet,
+    syntax_set: RwLock<SyntaxSet>,
+    config: RwLock<Config>,
+    changes: broadcast::Sender<()>,
 }
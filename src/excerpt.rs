@@ -0,0 +1,321 @@
+use markdown::mdast::{self, Node};
+use markdown::to_mdast;
+use syntect::parsing::SyntaxSet;
+
+use crate::config::MarkdownConfig;
+use crate::post::ParseError;
+use crate::render::{mdast_into_str_builder, parse_options, MarkdownError, RenderContext};
+use crate::utils::SlugMode;
+
+/// The start of a post, for index pages, feeds and meta descriptions.
+pub struct Excerpt {
+    pub html: String,
+    pub text: String,
+}
+
+/// Whether `node` is the `<!-- more -->` comment that ends a post's excerpt.
+fn is_more_marker(node: &Node) -> bool {
+    let Node::Html(mdast::Html { value, .. }) = node else {
+        return false;
+    };
+    value
+        .trim()
+        .strip_prefix("<!--")
+        .and_then(|comment| comment.strip_suffix("-->"))
+        .is_some_and(|comment| comment.trim().eq_ignore_ascii_case("more"))
+}
+
+/// The text of `nodes` without any markup, on one line.
+fn plain_text<'a>(nodes: impl IntoIterator<Item = &'a Node>) -> String {
+    fn collect(node: &Node, out: &mut String) {
+        match node {
+            Node::Text(mdast::Text { value, .. })
+            | Node::InlineCode(mdast::InlineCode { value, .. })
+            | Node::InlineMath(mdast::InlineMath { value, .. })
+            | Node::Code(mdast::Code { value, .. }) => out.push_str(value),
+            Node::Image(mdast::Image { alt, .. })
+            | Node::ImageReference(mdast::ImageReference { alt, .. }) => out.push_str(alt),
+            Node::Html(_)
+            | Node::FootnoteReference(_)
+            | Node::FootnoteDefinition(_)
+            | Node::Definition(_)
+            | Node::Yaml(_)
+            | Node::Toml(_) => {}
+            _ => {
+                for child in node.children().into_iter().flatten() {
+                    collect(child, out);
+                }
+            }
+        }
+        // Keep the words of separate blocks apart
+        if !matches!(
+            node,
+            Node::Text(_)
+                | Node::InlineCode(_)
+                | Node::InlineMath(_)
+                | Node::Emphasis(_)
+                | Node::Strong(_)
+                | Node::Delete(_)
+                | Node::Link(_)
+                | Node::LinkReference(_)
+        ) {
+            out.push(' ');
+        }
+    }
+
+    let mut text = String::new();
+    for node in nodes {
+        collect(node, &mut text);
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Cuts `text` down to at most `length` characters at a word boundary,
+/// marking that it's been cut with an ellipsis.
+fn truncate(text: String, length: usize) -> String {
+    if text.chars().count() <= length {
+        return text;
+    }
+    let end = text
+        .char_indices()
+        .nth(length)
+        .map_or(text.len(), |(i, _)| i);
+    let cut = match text[..end].rfind(' ') {
+        _ if text[end..].starts_with(' ') => &text[..end],
+        Some(space) if space > 0 => &text[..space],
+        _ => &text[..end],
+    };
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
+
+/// Cuts `nodes` down so their text is at most `length` characters, as
+/// [`truncate`] does, keeping the markup around what's left.
+fn truncate_nodes(nodes: &[&Node], length: usize) -> Vec<Node> {
+    /// `remaining` is `None` once the cut has been made.
+    fn cut(node: &Node, remaining: &mut Option<usize>, length: usize) -> Option<Node> {
+        let left = (*remaining)?;
+        let atomic_len = match node {
+            Node::InlineCode(mdast::InlineCode { value, .. })
+            | Node::InlineMath(mdast::InlineMath { value, .. }) => Some(value.chars().count()),
+            Node::Image(mdast::Image { alt, .. })
+            | Node::ImageReference(mdast::ImageReference { alt, .. }) => Some(alt.chars().count()),
+            _ => None,
+        };
+        match node {
+            Node::Text(text) => {
+                let len = text.value.chars().count();
+                if len <= left {
+                    *remaining = Some(left - len);
+                    return Some(node.clone());
+                }
+                *remaining = None;
+                // Rather than cut a word in half, leave it out if it isn't
+                // the first
+                let whole_words = text.value.chars().take(left + 1).any(|c| c == ' ');
+                let value = if whole_words || left == length {
+                    truncate(text.value.clone(), left)
+                } else {
+                    "…".to_owned()
+                };
+                Some(Node::Text(mdast::Text {
+                    value,
+                    position: text.position.clone(),
+                }))
+            }
+            _ => match atomic_len {
+                Some(len) if len <= left => {
+                    *remaining = Some(left - len);
+                    Some(node.clone())
+                }
+                Some(_) => {
+                    *remaining = None;
+                    Some(Node::Text(mdast::Text {
+                        value: "…".to_owned(),
+                        position: None,
+                    }))
+                }
+                None => {
+                    let mut node = node.clone();
+                    if let Some(children) = node.children_mut() {
+                        let kept = children
+                            .iter()
+                            .filter_map(|child| cut(child, remaining, length))
+                            .collect();
+                        *children = kept;
+                    }
+                    Some(node)
+                }
+            },
+        }
+    }
+
+    let mut remaining = Some(length);
+    nodes
+        .iter()
+        .filter_map(|node| cut(node, &mut remaining, length))
+        .collect()
+}
+
+/// The blocks of a post before its `<!-- more -->` comment, if it has one.
+/// Titles are left out.
+fn nodes_before_marker(root: &mdast::Root) -> Option<Vec<&Node>> {
+    root.children.iter().any(is_more_marker).then(|| {
+        root.children
+            .iter()
+            .take_while(|node| !is_more_marker(node))
+            .filter(|node| {
+                !matches!(
+                    node,
+                    Node::Yaml(_)
+                        | Node::Toml(_)
+                        | Node::Definition(_)
+                        | Node::FootnoteDefinition(_)
+                ) && !matches!(node, Node::Heading(heading) if heading.depth == 1)
+            })
+            .collect()
+    })
+}
+
+/// The paragraphs a post opens with: the first, and as many of those
+/// straight after it as fit in `length` characters. Anything else, such as
+/// a heading or code block, ends them.
+fn first_paragraphs(root: &mdast::Root, length: usize) -> Vec<&Node> {
+    let mut nodes = vec![];
+    let mut total = 0;
+    let blocks = root.children.iter().filter(|node| {
+        !matches!(
+            node,
+            Node::Yaml(_) | Node::Toml(_) | Node::Definition(_) | Node::FootnoteDefinition(_)
+        )
+    });
+    let mut blocks =
+        blocks.skip_while(|node| matches!(node, Node::Heading(heading) if heading.depth == 1));
+    while let Some(node @ Node::Paragraph(_)) = blocks.next() {
+        let len = plain_text([node]).chars().count();
+        if nodes.is_empty() {
+            total = len;
+        } else if total + 1 + len <= length {
+            total += 1 + len;
+        } else {
+            break;
+        }
+        nodes.push(node);
+    }
+    nodes
+}
+
+/// Builds a post's excerpt from its front matter `excerpt` (as Markdown) if
+/// it has one, otherwise from everything before a `<!-- more -->` comment,
+/// otherwise from its first paragraphs. Only the last is cut to `length`.
+/// Footnote references and heading ids are left out, as they'd point
+/// nowhere on listing pages and in feeds.
+pub fn excerpt(
+    md_ast: &Node,
+    front_matter: Option<&str>,
+    length: usize,
+    syntax_set: &SyntaxSet,
+    config: &MarkdownConfig,
+    slugs: SlugMode,
+) -> Result<Excerpt, ParseError> {
+    let front_matter_ast = front_matter
+        .map(|excerpt| to_mdast(excerpt, &parse_options()))
+        .transpose()
+        .map_err(|err| MarkdownError::ErrorParsing { wrapped: err })?;
+    let ast = front_matter_ast.as_ref().unwrap_or(md_ast);
+    let Node::Root(root) = ast else {
+        return Err(MarkdownError::InvalidRoot.into());
+    };
+    let (nodes, automatic) = match front_matter {
+        Some(_) => (root.children.iter().collect(), false),
+        None => match nodes_before_marker(root) {
+            Some(nodes) => (nodes, false),
+            None => (first_paragraphs(root, length), true),
+        },
+    };
+
+    let text = plain_text(nodes.iter().copied());
+    let truncated =
+        (automatic && text.chars().count() > length).then(|| truncate_nodes(&nodes, length));
+    let nodes = match &truncated {
+        Some(truncated) => truncated.iter().collect(),
+        None => nodes,
+    };
+
+    let ctx = RenderContext::excerpt(ast, syntax_set, config, slugs);
+    let mut builder = vec![];
+    for node in nodes {
+        mdast_into_str_builder(node, &mut builder, &ctx)?;
+    }
+    Ok(Excerpt {
+        html: builder.concat(),
+        text: if automatic {
+            truncate(text, length)
+        } else {
+            text
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excerpt_of(md: &str, front_matter: Option<&str>, length: usize) -> Excerpt {
+        let ast = to_mdast(md, &parse_options()).unwrap();
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let config = MarkdownConfig::default();
+        excerpt(
+            &ast,
+            front_matter,
+            length,
+            &syntax_set,
+            &config,
+            SlugMode::Ascii,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_excerpt() {
+        let md = "# Title\n\nFirst *para*.\n\nSecond para.\n\n<!-- more -->\n\nRest.";
+        let marked = excerpt_of(md, None, 5);
+        assert_eq!(
+            marked.html,
+            "<p>First <em>para</em>.</p><p>Second para.</p>"
+        );
+        assert_eq!(marked.text, "First para. Second para.");
+
+        let md = "# Title\n\nOne two three four.\n\nFive.\n\nSix.";
+        assert_eq!(excerpt_of("Ünïcödé wörds", None, 3).text, "Ünï…");
+        let auto = excerpt_of(md, None, 10);
+        assert_eq!(auto.html, "<p>One two…</p>");
+        assert_eq!(auto.text, "One two…");
+        let longer = excerpt_of(md, None, 25);
+        assert_eq!(longer.html, "<p>One two three four.</p><p>Five.</p>");
+        assert_eq!(longer.text, "One two three four. Five.");
+
+        let md = "# Title\n\nIntro.\n\n```\ncode\n```\n\nAfter code.\n\n## Section\n\nLater.";
+        assert_eq!(excerpt_of(md, None, 100).html, "<p>Intro.</p>");
+        let md = "Intro.\n\nSecond.\n\n## Section\n\nLater.";
+        assert_eq!(excerpt_of(md, None, 100).text, "Intro. Second.");
+
+        let long = excerpt_of("Some *emphasised words* and `code` here.", None, 12);
+        assert_eq!(long.html, "<p>Some <em>…</em></p>");
+        assert_eq!(long.text, "Some…");
+        let long = excerpt_of("Some *emphasised words* and `code` here.", None, 20);
+        assert_eq!(long.html, "<p>Some <em>emphasised…</em></p>");
+        assert_eq!(long.text, "Some emphasised…");
+        let code = excerpt_of("A long `inline code span`.", None, 8);
+        assert_eq!(code.html, "<p>A long …</p>");
+
+        let md = "# Title\n\n## Intro\n\nSee[^1].\n\n<!-- more -->\n\n[^1]: Note.";
+        assert_eq!(excerpt_of(md, None, 100).html, "<h2>Intro</h2><p>See.</p>");
+
+        let given = excerpt_of(md, Some("A `custom` excerpt"), 10);
+        assert_eq!(given.html, "<p>A <code>custom</code> excerpt</p>");
+        assert_eq!(given.text, "A custom excerpt");
+    }
+}
@@ -0,0 +1,230 @@
+use chrono::{DateTime, FixedOffset, Utc};
+use thiserror::Error;
+
+use crate::config::{Config, FeedContent};
+use crate::post::Post;
+use crate::utils::escape_html;
+
+#[derive(Error, Debug)]
+pub enum FeedError {
+    #[error("Feeds need absolute URLs. Set `base_url` under `[site]` in _config/site.toml")]
+    MissingBaseUrl,
+}
+
+struct Entry<'a> {
+    post: &'a Post,
+    url: String,
+    date: DateTime<FixedOffset>,
+}
+
+/// The newest `feed.limit` posts, newest first. Posts without a date, like
+/// an about page, aren't syndicated.
+fn entries<'a>(posts: &'a [Post], config: &Config) -> Result<Vec<Entry<'a>>, FeedError> {
+    let mut entries = posts
+        .iter()
+        .filter_map(|post| Some((post, post.metadata.datetime()?)))
+        .map(|(post, date)| {
+            Ok(Entry {
+                post,
+                url: config
+                    .absolute_url(&post.metadata.permalink)
+                    .ok_or(FeedError::MissingBaseUrl)?,
+                date,
+            })
+        })
+        .collect::<Result<Vec<_>, FeedError>>()?;
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.date));
+    entries.truncate(config.feed.limit);
+    Ok(entries)
+}
+
+/// The HTML to syndicate for a post, according to `feed.content`.
+fn entry_html<'a>(post: &'a Post, config: &Config) -> Option<&'a str> {
+    match config.feed.content {
+        FeedContent::Full => Some(&post.content),
+        FeedContent::Excerpt if post.metadata.excerpt.is_empty() => None,
+        FeedContent::Excerpt => Some(&post.metadata.excerpt),
+    }
+}
+
+/// Makes the site-relative `href` and `src` attributes in `html` absolute,
+/// for RSS, which has no `xml:base`. Fragment links such as footnotes are
+/// resolved against the post's own `url`.
+fn absolute_links(html: &str, url: &str, config: &Config) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(['h', 's']) {
+        let (before, at) = rest.split_at(start);
+        out.push_str(before);
+        let Some(attribute) = ["href=\"", "src=\""]
+            .into_iter()
+            .find(|attribute| at.starts_with(attribute))
+        else {
+            out.push_str(&at[..1]);
+            rest = &at[1..];
+            continue;
+        };
+        out.push_str(attribute);
+        rest = &at[attribute.len()..];
+        if rest.starts_with('#') {
+            out.push_str(url);
+        } else if rest.starts_with('/') && !rest.starts_with("//") {
+            // The base URL is known to be set by now
+            out.push_str(config.absolute_url("").as_deref().unwrap_or_default());
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Renders an Atom feed, to be served from `/feed.xml`.
+pub fn atom(posts: &[Post], config: &Config) -> Result<String, FeedError> {
+    let entries = entries(posts, config)?;
+    let home = config.absolute_url("/").ok_or(FeedError::MissingBaseUrl)?;
+    let updated = entries
+        .first()
+        .map_or_else(|| Utc::now().fixed_offset(), |entry| entry.date);
+    let author = config.site.author.as_ref().unwrap_or(&config.site.title);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out += &format!("<title>{}</title>\n", escape_html(&config.site.title));
+    out += &format!(
+        "<link href=\"{}\" rel=\"self\"/>\n",
+        escape_html(&format!("{}feed.xml", home))
+    );
+    out += &format!("<link href=\"{}\"/>\n", escape_html(&home));
+    out += &format!("<id>{}</id>\n", escape_html(&home));
+    out += &format!("<updated>{}</updated>\n", updated.to_rfc3339());
+    out += &format!("<author><name>{}</name></author>\n", escape_html(author));
+
+    for Entry { post, url, date } in &entries {
+        out.push_str("<entry>\n");
+        out += &format!("<title>{}</title>\n", escape_html(&post.metadata.title));
+        out += &format!("<link href=\"{}\"/>\n", escape_html(url));
+        out += &format!("<id>{}</id>\n", escape_html(url));
+        out += &format!("<published>{}</published>\n", date.to_rfc3339());
+        out += &format!("<updated>{}</updated>\n", date.to_rfc3339());
+        for tag in &post.metadata.tags {
+            out += &format!("<category term=\"{}\"/>\n", escape_html(&tag.name));
+        }
+        if let Some(html) = entry_html(post, config) {
+            // xml:base lets readers resolve the site-relative links in the post
+            let element = match config.feed.content {
+                FeedContent::Full => "content",
+                FeedContent::Excerpt => "summary",
+            };
+            out += &format!(
+                "<{element} type=\"html\" xml:base=\"{}\">{}</{element}>\n",
+                escape_html(url),
+                escape_html(html)
+            );
+        }
+        out.push_str("</entry>\n");
+    }
+    out.push_str("</feed>\n");
+    Ok(out)
+}
+
+/// Renders an RSS 2.0 feed, to be served from `/rss.xml`.
+pub fn rss(posts: &[Post], config: &Config) -> Result<String, FeedError> {
+    let entries = entries(posts, config)?;
+    let home = config.absolute_url("/").ok_or(FeedError::MissingBaseUrl)?;
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n");
+    out += &format!("<title>{}</title>\n", escape_html(&config.site.title));
+    out += &format!("<link>{}</link>\n", escape_html(&home));
+    out += &format!(
+        "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+        escape_html(&format!("{}rss.xml", home))
+    );
+    out += &format!(
+        "<description>{}</description>\n",
+        escape_html(&config.site.title)
+    );
+    if let Some(entry) = entries.first() {
+        out += &format!(
+            "<lastBuildDate>{}</lastBuildDate>\n",
+            entry.date.to_rfc2822()
+        );
+    }
+
+    for Entry { post, url, date } in &entries {
+        out.push_str("<item>\n");
+        out += &format!("<title>{}</title>\n", escape_html(&post.metadata.title));
+        out += &format!("<link>{}</link>\n", escape_html(url));
+        out += &format!("<guid isPermaLink=\"true\">{}</guid>\n", escape_html(url));
+        out += &format!("<pubDate>{}</pubDate>\n", date.to_rfc2822());
+        for tag in &post.metadata.tags {
+            out += &format!("<category>{}</category>\n", escape_html(&tag.name));
+        }
+        if let Some(html) = entry_html(post, config) {
+            out += &format!(
+                "<description>{}</description>\n",
+                escape_html(&absolute_links(html, url, config))
+            );
+        }
+        out.push_str("</item>\n");
+    }
+    out.push_str("</channel>\n</rss>\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::PostMeta;
+
+    fn post(title: &str, date: &str, content: &str) -> Post {
+        Post::with_content(PostMeta::dated(title, date), content)
+    }
+
+    #[test]
+    fn test_atom() {
+        let mut config = Config::default();
+        config.site.base_url = Some("https://example.com/".to_owned());
+        config.feed.limit = 1;
+        let posts = [
+            post("old", "2023-01-01", "<p>Old</p>"),
+            post("new", "2023-02-01", "<p>a &amp; b</p>"),
+        ];
+
+        let feed = atom(&posts, &config).unwrap();
+        assert!(feed.contains("<link href=\"https://example.com/new.html\"/>"));
+        assert!(feed.contains("&lt;p&gt;a &amp;amp; b&lt;/p&gt;"));
+        assert!(feed.contains("<updated>2023-02-01T00:00:00+00:00</updated>"));
+        assert!(!feed.contains("old.html"));
+    }
+
+    #[test]
+    fn test_rss_links_are_absolute() {
+        let mut config = Config::default();
+        config.site.base_url = Some("https://example.com/blog/".to_owned());
+        let posts = [post(
+            "a",
+            "2023-01-01",
+            "<p><a href=\"/b.html\">b</a><img src=\"/cat.png\" alt=\"\" /><a href=\"#fn-1\">1</a>\
+             <a href=\"https://other.org/\">c</a><a href=\"//cdn.org/x\">d</a> hrefs=\"/x\"</p>",
+        )];
+        let feed = rss(&posts, &config).unwrap();
+        assert!(feed.contains(
+            "&lt;a href=&quot;https://example.com/blog/b.html&quot;&gt;b&lt;/a&gt;\
+             &lt;img src=&quot;https://example.com/blog/cat.png&quot; alt=&quot;&quot; /&gt;\
+             &lt;a href=&quot;https://example.com/blog/a.html#fn-1&quot;&gt;1&lt;/a&gt;\
+             &lt;a href=&quot;https://other.org/&quot;&gt;c&lt;/a&gt;\
+             &lt;a href=&quot;//cdn.org/x&quot;&gt;d&lt;/a&gt; hrefs=&quot;/x&quot;"
+        ));
+    }
+
+    #[test]
+    fn test_feeds_need_base_url() {
+        let posts = [post("a", "2023-01-01", "")];
+        assert!(matches!(
+            rss(&posts, &Config::default()),
+            Err(FeedError::MissingBaseUrl)
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+use markdown::mdast::{self, Node};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::sanitise::RawHtml;
+
+#[derive(Error, Debug)]
+pub enum FrontMatterError {
+    #[error("Invalid YAML front matter. {source}")]
+    InvalidYaml {
+        #[from]
+        source: serde_yaml::Error,
+    },
+    #[error("Invalid TOML front matter. {source}")]
+    InvalidToml {
+        #[from]
+        source: toml::de::Error,
+    },
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    #[serde(deserialize_with = "deserialize_date")]
+    pub date: Option<String>,
+    pub slug: Option<String>,
+    pub excerpt: Option<String>,
+    pub tags: Vec<String>,
+    pub draft: bool,
+    /// Keep the post out of the sitemap
+    pub noindex: bool,
+    pub layout: Option<String>,
+    /// Overrides `site.permalink` from the site config
+    pub permalink: Option<String>,
+    /// Overrides `markdown.raw_html` from the site config
+    pub raw_html: Option<RawHtml>,
+}
+
+/// TOML has a native datetime type, so `date = 2023-08-01` arrives as a
+/// `Datetime` rather than a string.
+fn deserialize_date<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DateValue {
+        Text(String),
+        Toml(toml::value::Datetime),
+    }
+
+    Ok(match Option::<DateValue>::deserialize(deserializer)? {
+        Some(DateValue::Text(text)) => Some(text),
+        Some(DateValue::Toml(datetime)) => Some(datetime.to_string()),
+        None => None,
+    })
+}
+
+impl FrontMatter {
+    pub fn from_mdast(root: &mdast::Root) -> Result<FrontMatter, FrontMatterError> {
+        match root.children.first() {
+            Some(Node::Yaml(mdast::Yaml { value, .. })) => FrontMatter::from_yaml(value),
+            Some(Node::Toml(mdast::Toml { value, .. })) => FrontMatter::from_toml(value),
+            _ => Ok(FrontMatter::default()),
+        }
+    }
+
+    pub fn from_yaml(source: &str) -> Result<FrontMatter, FrontMatterError> {
+        // An empty block deserialises to a YAML null rather than an empty map
+        if source.trim().is_empty() {
+            return Ok(FrontMatter::default());
+        }
+        Ok(serde_yaml::from_str(source)?)
+    }
+
+    pub fn from_toml(source: &str) -> Result<FrontMatter, FrontMatterError> {
+        Ok(toml::from_str(source)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_yaml_front_matter() {
+        let fm = FrontMatter::from_yaml("title: Hello\ntags: [a, b]\ndraft: true").unwrap();
+        assert_eq!(fm.title.as_deref(), Some("Hello"));
+        assert_eq!(fm.tags, vec!["a", "b"]);
+        assert!(fm.draft);
+        assert_eq!(fm.slug, None);
+    }
+
+    #[test]
+    fn test_toml_front_matter() {
+        let fm = FrontMatter::from_toml("slug = \"hello\"\nlayout = \"wide\"\ndate = 2023-08-01")
+            .unwrap();
+        assert_eq!(fm.date.as_deref(), Some("2023-08-01"));
+        assert_eq!(fm.slug.as_deref(), Some("hello"));
+        assert_eq!(fm.layout.as_deref(), Some("wide"));
+        assert!(!fm.draft);
+    }
+
+    #[test]
+    fn test_wrongly_typed_front_matter() {
+        assert!(FrontMatter::from_yaml("draft: sometimes").is_err());
+        assert!(FrontMatter::from_toml("tags = \"one\"").is_err());
+        assert!(FrontMatter::from_yaml("title: [unclosed").is_err());
+    }
+
+    #[test]
+    fn test_unknown_front_matter_keys() {
+        let err = FrontMatter::from_yaml("title: Hi\ndraf: true").unwrap_err();
+        assert!(err.to_string().contains("unknown field `draf`"));
+        let err = FrontMatter::from_toml("draf = true").unwrap_err();
+        assert!(err.to_string().contains("unknown field `draf`"));
+    }
+}
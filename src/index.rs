@@ -1,10 +1,11 @@
-use crate::post::{Post, PostMeta};
-use crate::utils::files_within;
-use log::error;
+use crate::config::Config;
+use crate::post::{PostMeta, Tag};
 use ramhorns::Content;
-use std::path::{Path, PathBuf};
-use syntect::parsing::SyntaxSet;
+use std::collections::BTreeMap;
 
+/// Links between index pages. Page 1 holds the newest posts, so
+/// `first_page` is the front page and `latest_page` is the final page of
+/// the archive.
 #[derive(Content, Debug)]
 pub struct Pagenation {
     first_page: Option<String>,
@@ -14,47 +15,200 @@ pub struct Pagenation {
     page: u32,
     total_pages: u32,
 }
+
+#[derive(Content, Debug, Clone)]
+pub struct TagCount {
+    #[ramhorns(flatten)]
+    tag: Tag,
+    count: usize,
+}
+
+/// A listing of posts: a page of the main index, a page of the posts with
+/// a given tag, or (with no posts and no `tag`) the list of all tags.
 #[derive(Content, Debug)]
 pub struct Index {
+    permalink: String,
     posts: Vec<PostMeta>,
     pagenation: Option<Pagenation>,
+    /// The tag this page lists posts for, if it is a tag page
+    tag: Option<Tag>,
+    /// Every tag used on the site, alphabetically
+    tags: Vec<TagCount>,
+    #[ramhorns(skip)]
+    layout: &'static str,
+}
+
+/// The URL page `page` of a listing rooted at `base` is served from.
+pub fn page_permalink(base: &str, page: u32) -> String {
+    if page <= 1 {
+        base.to_owned()
+    } else {
+        format!("{base}page/{page}/")
+    }
 }
 
 impl Index {
-    pub fn from_file_list(site_root: &Path, posts: &[PathBuf], syntax_set: &SyntaxSet) -> Index {
-        let pagenation = if posts.len() > 20 {
-            error!("Need to paginate, but feature is not developed yet.");
-            Some(Pagenation {
-                first_page: Some("index.html".to_owned()),
-                previous_page: None,
-                next_page: None,
-                latest_page: Some("index.html".to_owned()),
-                page: 1,
-                total_pages: posts.len() as u32 / 20 + 1,
+    pub fn permalink(&self) -> &str {
+        &self.permalink
+    }
+
+    pub fn posts(&self) -> &[PostMeta] {
+        &self.posts
+    }
+
+    /// The name of the layout in `_config/layouts` this page is rendered with.
+    pub fn layout(&self) -> &'static str {
+        self.layout
+    }
+
+    /// Builds the main index, the list of tags and a listing for each tag,
+    /// each split into pages of at most `index.page_size` posts. `posts`
+    /// should already be sorted newest first.
+    pub fn from_posts(posts: Vec<PostMeta>, config: &Config) -> Vec<Index> {
+        let page_size = config.index.page_size;
+
+        let mut tagged: BTreeMap<&str, (&Tag, Vec<PostMeta>)> = BTreeMap::new();
+        for post in &posts {
+            for tag in &post.tags {
+                tagged
+                    .entry(&tag.slug)
+                    .or_insert_with(|| (tag, vec![]))
+                    .1
+                    .push(post.clone());
+            }
+        }
+        let tags: Vec<_> = tagged
+            .values()
+            .map(|(tag, posts)| TagCount {
+                tag: (*tag).clone(),
+                count: posts.len(),
             })
-        } else {
-            None
+            .collect();
+
+        let mut tag_pages = vec![];
+        if !tags.is_empty() {
+            tag_pages.push(Index {
+                permalink: Tag::INDEX_PERMALINK.to_owned(),
+                posts: vec![],
+                pagenation: None,
+                tag: None,
+                tags: tags.clone(),
+                layout: "tag",
+            });
+            for (tag, tag_posts) in tagged.values() {
+                tag_pages.extend(Index::paginate(
+                    tag_posts.clone(),
+                    page_size,
+                    Some(tag),
+                    &tags,
+                ));
+            }
+        }
+
+        let mut pages = Index::paginate(posts, page_size, None, &tags);
+        pages.append(&mut tag_pages);
+        pages
+    }
+
+    fn paginate(
+        mut posts: Vec<PostMeta>,
+        page_size: usize,
+        tag: Option<&Tag>,
+        tags: &[TagCount],
+    ) -> Vec<Index> {
+        let (base, layout) = match tag {
+            Some(tag) => (tag.permalink.as_str(), "tag"),
+            None => ("/", "index"),
         };
+        let total_pages = posts.len().div_ceil(page_size).max(1) as u32;
+        let mut pages = Vec::with_capacity(total_pages as usize);
+        for page in 1..=total_pages {
+            let rest = posts.split_off(page_size.min(posts.len()));
+            let pagenation = (total_pages > 1).then(|| Pagenation {
+                first_page: Some(page_permalink(base, 1)),
+                previous_page: (page > 1).then(|| page_permalink(base, page - 1)),
+                next_page: (page < total_pages).then(|| page_permalink(base, page + 1)),
+                latest_page: Some(page_permalink(base, total_pages)),
+                page,
+                total_pages,
+            });
+            pages.push(Index {
+                permalink: page_permalink(base, page),
+                posts,
+                pagenation,
+                tag: tag.cloned(),
+                tags: tags.to_vec(),
+                layout,
+            });
+            posts = rest;
+        }
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SlugMode;
+
+    fn post(title: &str, tags: &[&str]) -> PostMeta {
+        PostMeta {
+            tags: tags
+                .iter()
+                .map(|tag| Tag::new(tag, SlugMode::Ascii))
+                .collect(),
+            ..PostMeta::dated(title, "2023-08-01")
+        }
+    }
+
+    #[test]
+    fn test_paginate() {
+        let posts = (0..5).map(|i| post(&i.to_string(), &[])).collect();
+        let pages = Index::paginate(posts, 2, None, &[]);
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[2].posts.len(), 1);
+        assert_eq!(pages[2].permalink, "/page/3/");
 
-        let mut posts: Vec<_> = posts
+        let middle = pages[1].pagenation.as_ref().unwrap();
+        assert_eq!(middle.page, 2);
+        assert_eq!(middle.previous_page.as_deref(), Some("/"));
+        assert_eq!(middle.next_page.as_deref(), Some("/page/3/"));
+        assert_eq!(middle.latest_page.as_deref(), Some("/page/3/"));
+
+        let last = pages[2].pagenation.as_ref().unwrap();
+        assert_eq!(last.next_page, None);
+    }
+
+    #[test]
+    fn test_single_page_has_no_pagenation() {
+        let pages = Index::paginate(vec![post("a", &[])], 2, None, &[]);
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].pagenation.is_none());
+        assert_eq!(Index::paginate(vec![], 2, None, &[]).len(), 1);
+    }
+
+    #[test]
+    fn test_tag_pages() {
+        let mut config = Config::default();
+        config.index.page_size = 1;
+        let posts = vec![post("a", &["Rust", "Web"]), post("b", &["rust"])];
+
+        let pages = Index::from_posts(posts, &config);
+        let permalinks: Vec<_> = pages
             .iter()
-            .filter_map(|path| match Post::from_file(site_root, path, syntax_set) {
-                Ok(p) => Some(p.metadata),
-                Err(err) => {
-                    error!("Couldn't render page {}. {}", path.to_string_lossy(), err);
-                    None
-                }
-            })
+            .map(|index| (index.permalink(), index.layout()))
             .collect();
-        posts.sort_by_key(|p| p.published_date.clone());
-        posts.reverse();
-        Index { posts, pagenation }
-    }
-    pub fn from_path(folder: &Path, syntax_set: &SyntaxSet) -> Result<Index, std::io::Error> {
-        Ok(Index::from_file_list(
-            folder,
-            &files_within(folder)?,
-            syntax_set,
-        ))
+        assert_eq!(
+            permalinks,
+            [
+                ("/", "index"),
+                ("/page/2/", "index"),
+                ("/tags/", "tag"),
+                ("/tags/rust/", "tag"),
+                ("/tags/rust/page/2/", "tag"),
+                ("/tags/web/", "tag"),
+            ]
+        );
+        assert_eq!(pages[2].tags[0].count, 2);
     }
 }
@@ -1,15 +1,31 @@
 use clap::Parser;
-use index::Index;
-use log::{debug, info, Level, LevelFilter, Metadata, Record};
-use post::Post;
+use color_eyre::eyre::WrapErr;
+use config::Config;
+use log::{debug, error, info, warn, Level, LevelFilter, Metadata, Record};
+use post::{Post, SkipReason};
 use ramhorns::Template;
+use rayon::prelude::*;
+use site::Site;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use syntect::parsing::SyntaxSet;
 
+mod config;
+mod date;
 mod development_server;
+mod excerpt;
+mod feed;
+mod front_matter;
 mod index;
+mod math;
 mod post;
 mod render;
+mod sanitise;
+mod site;
+mod sitemap;
 mod utils;
 
 struct SimpleLogger;
@@ -38,6 +54,15 @@ enum Args {
         site_root: Option<PathBuf>,
         #[arg(long, default_value = "_site")]
         build_dir: PathBuf,
+        /// Publish posts marked `draft: true`
+        #[arg(long)]
+        drafts: bool,
+        /// Publish posts dated in the future
+        #[arg(long)]
+        future: bool,
+        /// How many posts to render at once. Defaults to the number of CPUs
+        #[arg(long, short)]
+        jobs: Option<NonZeroUsize>,
     },
     Serve {
         #[arg(long)]
@@ -67,6 +92,43 @@ fn load_syntax_set(site_root: &Path) -> color_eyre::eyre::Result<SyntaxSet> {
     Ok(ps)
 }
 
+fn load_layout(site_root: &Path, name: &str) -> color_eyre::eyre::Result<Template<'static>> {
+    let template_path = site_root
+        .join("_config/layouts")
+        .join(name)
+        .with_extension("hbs");
+    let template_source = std::fs::read_to_string(&template_path)
+        .wrap_err_with(|| format!("Couldn't find {name} template at {template_path:?}"))?;
+    Ok(Template::new(template_source)?)
+}
+
+fn create_output_file(path: &Path) -> std::io::Result<BufWriter<File>> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+/// Renders each of `pages` in parallel, with the template `page_info` gives
+/// for it, to the file its permalink is served from.
+fn write_pages<'a, T, F>(
+    pages: &'a [T],
+    build_dir: &Path,
+    config: &Config,
+    page_info: F,
+) -> color_eyre::eyre::Result<()>
+where
+    T: ramhorns::Content + Sync,
+    F: Fn(&'a T) -> (&'a str, &'a Template<'static>) + Sync,
+{
+    pages.par_iter().try_for_each(|page| {
+        let (permalink, template) = page_info(page);
+        let out_file_path = build_dir.join(utils::permalink_to_path(permalink));
+        let mut out_file = create_output_file(&out_file_path)?;
+        template
+            .render_to_writer(&mut out_file, &config.page(page))
+            .wrap_err_with(|| format!("Couldn't write {out_file_path:?}"))
+    })
+}
+
 #[tokio::main]
 async fn main() -> color_eyre::eyre::Result<()> {
     color_eyre::install()?;
@@ -78,53 +140,138 @@ async fn main() -> color_eyre::eyre::Result<()> {
         Args::Build {
             site_root,
             build_dir,
+            drafts,
+            future,
+            jobs,
         } => {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs.map_or(0, NonZeroUsize::get))
+                .build_global()?;
             let site_root = site_root.unwrap_or(".".into());
+            let config = Config::from_site_root(&site_root)?;
             let syntax_set: SyntaxSet = load_syntax_set(&site_root)?;
-            let post_template = {
-                let template_path = site_root.join("_config/layouts/post.hbs");
-                let template_source =
-                    std::fs::read_to_string(&template_path).unwrap_or_else(|_| {
-                        panic!("Couldn't find index template at {:?}", &template_path)
-                    });
-                Template::new(template_source)?
-            };
-            let index_template = {
-                let template_path = site_root.join("_config/layouts/index.hbs");
-                let template_source =
-                    std::fs::read_to_string(&template_path).unwrap_or_else(|_| {
-                        panic!("Couldn't find post template at {:?}", &template_path)
-                    });
-                Template::new(template_source)?
-            };
-
-            let all_site = utils::files_within(&site_root)?;
-            for md_file in &all_site {
-                let mut out_file_path = build_dir.join(md_file);
-                out_file_path.set_extension("html");
+            let mut layouts = HashMap::new();
+            for name in ["post", "index"] {
+                layouts.insert(name.to_owned(), load_layout(&site_root, name)?);
+            }
 
-                std::fs::create_dir_all(out_file_path.parent().unwrap())?;
+            let all_site = utils::files_within(&site_root, Some(&build_dir))?;
+            let parsed: Vec<_> = all_site
+                .par_iter()
+                .map(|md_file| Post::from_file(&site_root, md_file, &syntax_set, &config))
+                .collect();
+            let mut posts = Vec::with_capacity(all_site.len());
+            let mut failed = 0;
+            let mut sources = HashMap::new();
+            let (mut skipped_drafts, mut skipped_scheduled) = (0, 0);
+            for (md_file, post) in all_site.iter().zip(parsed) {
+                let post = match post {
+                    Ok(post) => post,
+                    Err(err) => {
+                        error!("Couldn't render {md_file:?}. {err}");
+                        failed += 1;
+                        continue;
+                    }
+                };
+                match post.metadata.skip_reason(drafts, future) {
+                    Some(SkipReason::Draft) => {
+                        debug!("Skipping draft {md_file:?}");
+                        skipped_drafts += 1;
+                        continue;
+                    }
+                    Some(SkipReason::Scheduled) => {
+                        debug!("Skipping scheduled post {md_file:?}");
+                        skipped_scheduled += 1;
+                        continue;
+                    }
+                    None => {}
+                }
+                let permalink = &post.metadata.permalink;
+                if let Some(other) = sources.insert(permalink.clone(), md_file) {
+                    color_eyre::eyre::bail!(
+                        "{other:?} and {md_file:?} both have the permalink {permalink}"
+                    );
+                }
+                posts.push(post);
+            }
+            if failed > 0 {
+                color_eyre::eyre::bail!(
+                    "{failed} of {} posts couldn't be rendered",
+                    all_site.len()
+                );
+            }
+            if skipped_drafts + skipped_scheduled > 0 {
+                info!(
+                    "Skipped {skipped_drafts} drafts and {skipped_scheduled} scheduled posts. \
+                     Publish them with --drafts and --future"
+                );
+            }
 
-                let post = Post::from_file(&site_root, md_file, &syntax_set)?;
-                let mut out_file = std::io::BufWriter::new(std::fs::File::create(out_file_path)?);
-                post_template.render_to_writer(&mut out_file, &post)?;
+            let site = Site::new(posts);
+            let pages = site.indexes(&config);
+            for index in &pages {
+                if let Some(md_file) = sources.get(index.permalink()) {
+                    color_eyre::eyre::bail!(
+                        "{md_file:?} has the permalink {}, which a listing page is served from",
+                        index.permalink()
+                    );
+                }
             }
+            let layout_names = site
+                .posts()
+                .iter()
+                .filter_map(|post| post.metadata.layout.as_deref())
+                .chain(pages.iter().map(|index| index.layout()));
+            for name in layout_names {
+                if !layouts.contains_key(name) {
+                    layouts.insert(name.to_owned(), load_layout(&site_root, name)?);
+                }
+            }
+
+            write_pages(site.posts(), &build_dir, &config, |post| {
+                let layout = post.metadata.layout.as_deref().unwrap_or("post");
+                (&post.metadata.permalink, &layouts[layout])
+            })?;
+            write_pages(&pages, &build_dir, &config, |index| {
+                (index.permalink(), &layouts[index.layout()])
+            })?;
 
-            let mut out_file = {
-                let index_path = build_dir.join("index.html");
-                std::io::BufWriter::new(std::fs::File::create(index_path)?)
-            };
-            index_template.render_to_writer(
-                &mut out_file,
-                &Index::from_file_list(&site_root, &all_site, &syntax_set),
-            )?;
+            let assets = utils::assets_within(&site_root, &build_dir)?;
+            for asset in &assets {
+                let out_file_path = build_dir.join(asset);
+                std::fs::create_dir_all(out_file_path.parent().unwrap())?;
+                std::fs::copy(site_root.join(asset), out_file_path)
+                    .wrap_err_with(|| format!("Couldn't copy {asset:?}"))?;
+            }
+            info!("Copied {} static files", assets.len());
+
+            if config.site.base_url.is_none() {
+                warn!("Not writing feeds or sitemap as `base_url` isn't set in _config/site.toml");
+            } else {
+                if config.feed.atom {
+                    create_output_file(&build_dir.join("feed.xml"))?
+                        .write_all(feed::atom(site.posts(), &config)?.as_bytes())?;
+                }
+                if config.feed.rss {
+                    create_output_file(&build_dir.join("rss.xml"))?
+                        .write_all(feed::rss(site.posts(), &config)?.as_bytes())?;
+                }
+                create_output_file(&build_dir.join("sitemap.xml"))?
+                    .write_all(sitemap::sitemap(site.posts(), &pages, &config)?.as_bytes())?;
+                // A hand-written robots.txt has already been copied over with the assets
+                if !site_root.join("robots.txt").exists() {
+                    create_output_file(&build_dir.join("robots.txt"))?
+                        .write_all(sitemap::robots_txt(&config)?.as_bytes())?;
+                }
+            }
 
             Ok(())
         }
         Args::Serve { site_root } => {
             let site_root = site_root.unwrap_or(".".into());
+            let config = Config::from_site_root(&site_root)?;
             let syntax_set: SyntaxSet = load_syntax_set(&site_root)?;
-            Ok(development_server::serve_forever(site_root, syntax_set).await?)
+            Ok(development_server::serve_forever(site_root, syntax_set, config).await?)
         }
     }
 }
@@ -0,0 +1,586 @@
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::utils::escape_html;
+
+/// How `$...$` and `$$...$$` blocks are written out.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MathMode {
+    /// Converted to MathML at build time, so no JavaScript is needed.
+    #[default]
+    MathMl,
+    /// Left as TeX inside `\(...\)` / `\[...\]` for KaTeX's auto-render.
+    Katex,
+}
+
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct TexError {
+    pub message: String,
+    /// Byte offset into the TeX source where the problem was found
+    pub offset: usize,
+}
+
+/// Converts a TeX formula into a `<math>` element. Only the commonly used
+/// subset of TeX is understood; anything else is reported as an error
+/// rather than rendered incorrectly.
+pub fn tex_to_mathml(tex: &str, display: bool) -> Result<String, TexError> {
+    let mut parser = Parser {
+        src: tex,
+        pos: 0,
+        display,
+    };
+    let row = parser.parse_row(RowEnd::Eof)?;
+    Ok(if display {
+        format!("<math display=\"block\">{row}</math>")
+    } else {
+        format!("<math>{row}</math>")
+    })
+}
+
+/// Wraps TeX in the delimiters KaTeX's auto-render extension looks for.
+pub fn tex_to_katex(tex: &str, display: bool) -> String {
+    let escaped = escape_html(tex);
+    if display {
+        format!("<div class=\"math math-display\">\\[{escaped}\\]</div>")
+    } else {
+        format!("<span class=\"math math-inline\">\\({escaped}\\)</span>")
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum RowEnd {
+    Eof,
+    Brace,
+    Right,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    display: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, offset: usize, message: impl Into<String>) -> Result<T, TexError> {
+        Err(TexError {
+            message: message.into(),
+            offset,
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+    }
+
+    fn starts_with_command(&self, name: &str) -> bool {
+        let rest = &self.src[self.pos..];
+        rest.strip_prefix('\\')
+            .and_then(|rest| rest.strip_prefix(name))
+            .is_some_and(|after| !after.starts_with(|c: char| c.is_ascii_alphabetic()))
+    }
+
+    fn parse_row(&mut self, end: RowEnd) -> Result<String, TexError> {
+        let start = self.pos;
+        let mut items = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None if end == RowEnd::Eof => break,
+                None if end == RowEnd::Brace => {
+                    return self.error(start.saturating_sub(1), "Unclosed `{`")
+                }
+                None => return self.error(start, "`\\left` without a matching `\\right`"),
+                Some('}') if end == RowEnd::Brace => break,
+                Some('}') => return self.error(self.pos, "Unexpected `}`"),
+                Some('\\') if self.starts_with_command("right") => {
+                    if end == RowEnd::Right {
+                        break;
+                    }
+                    return self.error(self.pos, "`\\right` without a matching `\\left`");
+                }
+                Some(_) => items.push(self.parse_atom()?),
+            }
+        }
+        Ok(mrow(items))
+    }
+
+    /// A base followed by any number of sub/superscripts.
+    fn parse_atom(&mut self) -> Result<String, TexError> {
+        let (base, large_op) = match self.peek() {
+            Some('^') | Some('_') => ("<mrow></mrow>".to_owned(), false),
+            _ => self.parse_base()?,
+        };
+
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            self.skip_whitespace();
+            let script_pos = self.pos;
+            match self.peek() {
+                Some('\'') => {
+                    // Primes are superscripts in disguise
+                    self.bump();
+                    if sup.is_some() {
+                        return self.error(script_pos, "Double superscript");
+                    }
+                    sup = Some("<mo>&#x2032;</mo>".to_owned());
+                }
+                Some(c @ ('_' | '^')) => {
+                    self.bump();
+                    let arg = self.parse_argument()?;
+                    let (slot, message) = if c == '_' {
+                        (&mut sub, "Double subscript")
+                    } else {
+                        (&mut sup, "Double superscript")
+                    };
+                    if slot.is_some() {
+                        return self.error(script_pos, message);
+                    }
+                    *slot = Some(arg);
+                }
+                _ => break,
+            }
+        }
+
+        let under_over = large_op && self.display;
+        Ok(match (sub, sup) {
+            (None, None) => base,
+            (Some(sub), None) if under_over => format!("<munder>{base}{sub}</munder>"),
+            (Some(sub), None) => format!("<msub>{base}{sub}</msub>"),
+            (None, Some(sup)) if under_over => format!("<mover>{base}{sup}</mover>"),
+            (None, Some(sup)) => format!("<msup>{base}{sup}</msup>"),
+            (Some(sub), Some(sup)) if under_over => {
+                format!("<munderover>{base}{sub}{sup}</munderover>")
+            }
+            (Some(sub), Some(sup)) => format!("<msubsup>{base}{sub}{sup}</msubsup>"),
+        })
+    }
+
+    /// A single token or braced group, as taken by commands and scripts.
+    fn parse_argument(&mut self) -> Result<String, TexError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => self.error(self.pos, "Missing argument"),
+            Some('^') | Some('_') => self.error(self.pos, "Missing argument"),
+            _ => Ok(self.parse_base()?.0),
+        }
+    }
+
+    /// Reads a braced group verbatim, for commands like `\text`.
+    fn parse_raw_group(&mut self) -> Result<&'a str, TexError> {
+        self.skip_whitespace();
+        let open = self.pos;
+        if self.bump() != Some('{') {
+            return self.error(open, "Expected `{`");
+        }
+        let mut depth = 1;
+        while let Some(c) = self.bump() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(&self.src[open + 1..self.pos - 1]);
+                    }
+                }
+                '\\' => {
+                    self.bump();
+                }
+                _ => {}
+            }
+        }
+        self.error(open, "Unclosed `{`")
+    }
+
+    /// Returns the MathML for the next token and whether it is a large
+    /// operator that takes limits above and below in display mode.
+    fn parse_base(&mut self) -> Result<(String, bool), TexError> {
+        let start = self.pos;
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return self.error(start, "Unexpected end of formula"),
+        };
+        let base = match c {
+            '{' => {
+                let row = self.parse_row(RowEnd::Brace)?;
+                self.bump();
+                row
+            }
+            '}' => return self.error(start, "Unexpected `}`"),
+            '\\' => return self.parse_command(start),
+            '0'..='9' | '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.bump();
+                }
+                format!("<mn>{}</mn>", &self.src[start..self.pos])
+            }
+            '&' => {
+                return self.error(
+                    start,
+                    "Alignment `&` is only allowed in environments, which aren't supported",
+                )
+            }
+            '#' | '$' | '%' => return self.error(start, format!("Unexpected `{c}`")),
+            '~' => "<mspace width=\"0.333em\"></mspace>".to_owned(),
+            c if c.is_alphabetic() => format!("<mi>{c}</mi>"),
+            '<' => "<mo>&lt;</mo>".to_owned(),
+            '>' => "<mo>&gt;</mo>".to_owned(),
+            '-' => "<mo>&#x2212;</mo>".to_owned(),
+            '*' => "<mo>&#x2217;</mo>".to_owned(),
+            '(' | ')' | '[' | ']' | '|' => format!("<mo stretchy=\"false\">{c}</mo>"),
+            c => format!("<mo>{c}</mo>"),
+        };
+        Ok((base, false))
+    }
+
+    fn parse_command(&mut self, start: usize) -> Result<(String, bool), TexError> {
+        let name_start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.bump();
+                }
+            }
+            Some(_) => {
+                self.bump();
+            }
+            None => return self.error(start, "Expected a command name after `\\`"),
+        }
+        let name = &self.src[name_start..self.pos];
+
+        if let Some(symbol) = symbol(name) {
+            return Ok(match symbol {
+                Symbol::Ident(s) => (format!("<mi>{s}</mi>"), false),
+                Symbol::Op(s) => (format!("<mo>{s}</mo>"), false),
+                Symbol::LargeOp(s) => (format!("<mo largeop=\"true\">{s}</mo>"), true),
+                Symbol::Integral(s) => (format!("<mo largeop=\"true\">{s}</mo>"), false),
+                Symbol::Function(s) => (format!("<mi>{s}</mi>"), false),
+                Symbol::Limit(s) => (format!("<mo movablelimits=\"true\">{s}</mo>"), true),
+                Symbol::Space(width) => (format!("<mspace width=\"{width}\"></mspace>"), false),
+            });
+        }
+
+        let base = match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let numerator = self.parse_argument()?;
+                let denominator = self.parse_argument()?;
+                format!("<mfrac>{numerator}{denominator}</mfrac>")
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                if self.peek() == Some('[') {
+                    self.bump();
+                    let index_start = self.pos;
+                    let close = match self.src[self.pos..].find(']') {
+                        Some(close) => self.pos + close,
+                        None => return self.error(index_start - 1, "Unclosed `[`"),
+                    };
+                    let index = {
+                        let mut inner = Parser {
+                            src: &self.src[..close],
+                            pos: index_start,
+                            display: self.display,
+                        };
+                        inner.parse_row(RowEnd::Eof)?
+                    };
+                    self.pos = close + 1;
+                    let radicand = self.parse_argument()?;
+                    format!("<mroot>{radicand}{index}</mroot>")
+                } else {
+                    format!("<msqrt>{}</msqrt>", self.parse_argument()?)
+                }
+            }
+            "left" => {
+                let open = self.parse_delimiter()?;
+                let row = self.parse_row(RowEnd::Right)?;
+                self.pos += "\\right".len();
+                let close = self.parse_delimiter()?;
+                format!("<mrow>{open}{row}{close}</mrow>")
+            }
+            "text" | "textrm" | "mbox" => {
+                format!("<mtext>{}</mtext>", escape_html(self.parse_raw_group()?))
+            }
+            "mathrm" | "operatorname" => self.parse_variant("normal")?,
+            "mathbf" => self.parse_variant("bold")?,
+            "mathit" => self.parse_variant("italic")?,
+            "mathbb" => self.parse_variant("double-struck")?,
+            "mathcal" => self.parse_variant("script")?,
+            "mathfrak" => self.parse_variant("fraktur")?,
+            "hat" | "widehat" => self.parse_accent("^")?,
+            "bar" | "overline" => self.parse_accent("&#x00AF;")?,
+            "vec" => self.parse_accent("&#x2192;")?,
+            "dot" => self.parse_accent("&#x02D9;")?,
+            "ddot" => self.parse_accent("&#x00A8;")?,
+            "tilde" | "widetilde" => self.parse_accent("~")?,
+            "right" => return self.error(start, "`\\right` without a matching `\\left`"),
+            "begin" | "end" => {
+                return self.error(start, "Environments (`\\begin`/`\\end`) aren't supported")
+            }
+            _ => return self.error(start, format!("Unknown command `\\{name}`")),
+        };
+        Ok((base, false))
+    }
+
+    fn parse_variant(&mut self, variant: &str) -> Result<String, TexError> {
+        let text = self.parse_raw_group()?;
+        Ok(format!(
+            "<mi mathvariant=\"{variant}\">{}</mi>",
+            escape_html(text.trim())
+        ))
+    }
+
+    fn parse_accent(&mut self, accent: &str) -> Result<String, TexError> {
+        let base = self.parse_argument()?;
+        Ok(format!(
+            "<mover accent=\"true\">{base}<mo>{accent}</mo></mover>"
+        ))
+    }
+
+    fn parse_delimiter(&mut self) -> Result<String, TexError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let delimiter = match self.bump() {
+            Some('.') => return Ok(String::new()),
+            Some('<') => "&lt;".to_owned(),
+            Some('>') => "&gt;".to_owned(),
+            Some('\\') => {
+                let (base, _) = self.parse_command(start)?;
+                return Ok(base);
+            }
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && c != '{' && c != '}' => {
+                c.to_string()
+            }
+            _ => return self.error(start, "Expected a delimiter after `\\left` or `\\right`"),
+        };
+        Ok(format!("<mo>{delimiter}</mo>"))
+    }
+}
+
+fn mrow(items: Vec<String>) -> String {
+    if items.len() == 1 {
+        items.into_iter().next().unwrap()
+    } else {
+        format!("<mrow>{}</mrow>", items.concat())
+    }
+}
+
+enum Symbol {
+    Ident(&'static str),
+    Op(&'static str),
+    LargeOp(&'static str),
+    /// Large operators whose limits stay to the side, even in display mode
+    Integral(&'static str),
+    Function(&'static str),
+    Limit(&'static str),
+    Space(&'static str),
+}
+
+fn symbol(name: &str) -> Option<Symbol> {
+    use Symbol::*;
+    Some(match name {
+        "alpha" => Ident("α"),
+        "beta" => Ident("β"),
+        "gamma" => Ident("γ"),
+        "delta" => Ident("δ"),
+        "epsilon" => Ident("ϵ"),
+        "varepsilon" => Ident("ε"),
+        "zeta" => Ident("ζ"),
+        "eta" => Ident("η"),
+        "theta" => Ident("θ"),
+        "vartheta" => Ident("ϑ"),
+        "iota" => Ident("ι"),
+        "kappa" => Ident("κ"),
+        "lambda" => Ident("λ"),
+        "mu" => Ident("μ"),
+        "nu" => Ident("ν"),
+        "xi" => Ident("ξ"),
+        "pi" => Ident("π"),
+        "varpi" => Ident("ϖ"),
+        "rho" => Ident("ρ"),
+        "varrho" => Ident("ϱ"),
+        "sigma" => Ident("σ"),
+        "varsigma" => Ident("ς"),
+        "tau" => Ident("τ"),
+        "upsilon" => Ident("υ"),
+        "phi" => Ident("ϕ"),
+        "varphi" => Ident("φ"),
+        "chi" => Ident("χ"),
+        "psi" => Ident("ψ"),
+        "omega" => Ident("ω"),
+        "Gamma" => Ident("Γ"),
+        "Delta" => Ident("Δ"),
+        "Theta" => Ident("Θ"),
+        "Lambda" => Ident("Λ"),
+        "Xi" => Ident("Ξ"),
+        "Pi" => Ident("Π"),
+        "Sigma" => Ident("Σ"),
+        "Upsilon" => Ident("Υ"),
+        "Phi" => Ident("Φ"),
+        "Psi" => Ident("Ψ"),
+        "Omega" => Ident("Ω"),
+        "infty" => Ident("∞"),
+        "partial" => Ident("∂"),
+        "nabla" => Ident("∇"),
+        "emptyset" => Ident("∅"),
+        "hbar" => Ident("ℏ"),
+        "ell" => Ident("ℓ"),
+        "ldots" | "dots" => Op("…"),
+        "cdots" => Op("⋯"),
+        "vdots" => Op("⋮"),
+        "ddots" => Op("⋱"),
+        "pm" => Op("±"),
+        "mp" => Op("∓"),
+        "times" => Op("×"),
+        "div" => Op("÷"),
+        "cdot" => Op("⋅"),
+        "ast" => Op("∗"),
+        "circ" => Op("∘"),
+        "leq" | "le" => Op("≤"),
+        "geq" | "ge" => Op("≥"),
+        "neq" | "ne" => Op("≠"),
+        "approx" => Op("≈"),
+        "equiv" => Op("≡"),
+        "sim" => Op("∼"),
+        "simeq" => Op("≃"),
+        "propto" => Op("∝"),
+        "ll" => Op("≪"),
+        "gg" => Op("≫"),
+        "in" => Op("∈"),
+        "notin" => Op("∉"),
+        "ni" => Op("∋"),
+        "subset" => Op("⊂"),
+        "subseteq" => Op("⊆"),
+        "supset" => Op("⊃"),
+        "supseteq" => Op("⊇"),
+        "cup" => Op("∪"),
+        "cap" => Op("∩"),
+        "setminus" => Op("∖"),
+        "land" | "wedge" => Op("∧"),
+        "lor" | "vee" => Op("∨"),
+        "neg" | "lnot" => Op("¬"),
+        "forall" => Op("∀"),
+        "exists" => Op("∃"),
+        "to" | "rightarrow" => Op("→"),
+        "leftarrow" | "gets" => Op("←"),
+        "leftrightarrow" => Op("↔"),
+        "Rightarrow" | "implies" => Op("⇒"),
+        "Leftarrow" => Op("⇐"),
+        "Leftrightarrow" | "iff" => Op("⇔"),
+        "mapsto" => Op("↦"),
+        "mid" => Op("∣"),
+        "parallel" => Op("∥"),
+        "perp" => Op("⊥"),
+        "langle" => Op("⟨"),
+        "rangle" => Op("⟩"),
+        "lfloor" => Op("⌊"),
+        "rfloor" => Op("⌋"),
+        "lceil" => Op("⌈"),
+        "rceil" => Op("⌉"),
+        "{" | "lbrace" => Op("{"),
+        "}" | "rbrace" => Op("}"),
+        "|" => Op("‖"),
+        "_" => Op("_"),
+        "%" => Op("%"),
+        "$" => Op("$"),
+        "#" => Op("#"),
+        "&" => Op("&amp;"),
+        "backslash" => Op("\\"),
+        "sum" => LargeOp("∑"),
+        "prod" => LargeOp("∏"),
+        "coprod" => LargeOp("∐"),
+        "int" => Integral("∫"),
+        "iint" => Integral("∬"),
+        "iiint" => Integral("∭"),
+        "oint" => Integral("∮"),
+        "bigcup" => LargeOp("⋃"),
+        "bigcap" => LargeOp("⋂"),
+        "sin" => Function("sin"),
+        "cos" => Function("cos"),
+        "tan" => Function("tan"),
+        "cot" => Function("cot"),
+        "sec" => Function("sec"),
+        "csc" => Function("csc"),
+        "arcsin" => Function("arcsin"),
+        "arccos" => Function("arccos"),
+        "arctan" => Function("arctan"),
+        "sinh" => Function("sinh"),
+        "cosh" => Function("cosh"),
+        "tanh" => Function("tanh"),
+        "log" => Function("log"),
+        "ln" => Function("ln"),
+        "exp" => Function("exp"),
+        "det" => Function("det"),
+        "dim" => Function("dim"),
+        "deg" => Function("deg"),
+        "arg" => Function("arg"),
+        "gcd" => Function("gcd"),
+        "lim" => Limit("lim"),
+        "max" => Limit("max"),
+        "min" => Limit("min"),
+        "sup" => Limit("sup"),
+        "inf" => Limit("inf"),
+        "," => Space("0.167em"),
+        ":" | ">" => Space("0.222em"),
+        ";" => Space("0.278em"),
+        " " => Space("0.333em"),
+        "!" => Space("-0.167em"),
+        "quad" => Space("1em"),
+        "qquad" => Space("2em"),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tex_to_mathml() {
+        assert_eq!(
+            tex_to_mathml("x^2", false).unwrap(),
+            "<math><msup><mi>x</mi><mn>2</mn></msup></math>"
+        );
+        assert_eq!(
+            tex_to_mathml("\\frac{a}{b}", true).unwrap(),
+            "<math display=\"block\"><mfrac><mi>a</mi><mi>b</mi></mfrac></math>"
+        );
+        assert_eq!(
+            tex_to_mathml("\\sum_{i=0}^n i", true).unwrap(),
+            "<math display=\"block\"><mrow><munderover><mo largeop=\"true\">∑</mo>\
+             <mrow><mi>i</mi><mo>=</mo><mn>0</mn></mrow><mi>n</mi></munderover>\
+             <mi>i</mi></mrow></math>"
+        );
+        assert_eq!(
+            tex_to_mathml("a < b", false).unwrap(),
+            "<math><mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn test_invalid_tex() {
+        let err = tex_to_mathml("1 + \\fracc{1}{2}", false).unwrap_err();
+        assert_eq!(err.message, "Unknown command `\\fracc`");
+        assert_eq!(err.offset, 4);
+
+        let err = tex_to_mathml("\\frac{1}{2", false).unwrap_err();
+        assert_eq!(err.message, "Unclosed `{`");
+        assert_eq!(err.offset, 8);
+
+        assert!(tex_to_mathml("x^2^3", false).is_err());
+        assert!(tex_to_mathml("\\left( x", false).is_err());
+    }
+}
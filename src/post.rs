@@ -1,27 +1,116 @@
-use std::{
-    io,
-    path::{Path, PathBuf},
-};
+use std::{io, path::Path, time::SystemTime};
 
-use crate::render::{mdast_into_str_builder, MarkdownError, RenderError, Toc};
-use markdown::{mdast, to_mdast, ParseOptions};
+use crate::config::{Config, MarkdownConfig};
+use crate::date::{parse_date, PostDate};
+use crate::excerpt::excerpt;
+use crate::front_matter::{FrontMatter, FrontMatterError};
+use crate::render::{
+    footnotes_into_str_builder, mdast_into_str_builder, parse_options, MarkdownError,
+    RenderContext, RenderError, Toc,
+};
+use crate::utils::{parameterize, SlugMode};
+use chrono::{DateTime, FixedOffset, Utc};
+use log::warn;
+use markdown::{mdast, to_mdast};
 use ramhorns::Content;
 use syntect::parsing::SyntaxSet;
 use thiserror::Error;
 
-#[derive(Content, Debug)]
+#[derive(Content, Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub slug: String,
+    pub permalink: String,
+}
+
+impl Tag {
+    /// Where the list of every tag is served from.
+    pub const INDEX_PERMALINK: &'static str = "/tags/";
+
+    pub fn new(name: &str, slugs: SlugMode) -> Tag {
+        match parameterize(name, slugs) {
+            slug if slug.is_empty() => Tag::with_slug(name, "tag".to_owned()),
+            slug => Tag::with_slug(name, slug.into_owned()),
+        }
+    }
+
+    pub fn with_slug(name: &str, slug: String) -> Tag {
+        Tag {
+            name: name.to_owned(),
+            permalink: format!("{}{slug}/", Tag::INDEX_PERMALINK),
+            slug,
+        }
+    }
+}
+
+#[derive(Content, Debug, Default, Clone)]
 pub struct PostMeta {
     pub permalink: String,
     pub title: String,
+    /// The day the post was published, as `YYYY-MM-DD`. Empty if it has no
+    /// date.
     pub published_date: String,
+    /// When the post was published, from its front matter or filename
+    pub date: Option<PostDate>,
+    pub slug: String,
+    /// The start of the post as HTML, see [`crate::excerpt::excerpt`]
     pub excerpt: String,
+    /// The excerpt as plain text, e.g. for `<meta name="description">`
+    pub excerpt_text: String,
+    pub tags: Vec<Tag>,
+    pub draft: bool,
+    /// Why the post isn't published yet, e.g. `Draft`, for layouts to show
+    /// in a banner. `rhodium build` leaves such posts out unless asked.
+    pub unpublished: Option<String>,
+    pub noindex: bool,
+    pub layout: Option<String>,
+    /// When the source file was last changed
+    #[ramhorns(skip)]
+    pub modified: Option<SystemTime>,
+}
+
+impl PostMeta {
+    pub fn datetime(&self) -> Option<DateTime<FixedOffset>> {
+        self.date.as_ref().map(|date| date.datetime)
+    }
+
+    /// Whether the post is dated in the future.
+    pub fn is_scheduled(&self) -> bool {
+        self.datetime().is_some_and(|date| date > Utc::now())
+    }
+
+    /// Why `rhodium build` leaves the post out, if it does. `drafts` and
+    /// `future` publish drafts and scheduled posts anyway.
+    pub fn skip_reason(&self, drafts: bool, future: bool) -> Option<SkipReason> {
+        if self.draft && !drafts {
+            Some(SkipReason::Draft)
+        } else if self.is_scheduled() && !future {
+            Some(SkipReason::Scheduled)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    Draft,
+    /// Dated in the future
+    Scheduled,
 }
 
 #[derive(Content, Debug)]
 pub struct Post {
     pub metadata: PostMeta,
+    /// The table of contents as HTML, if the post has any sections
     pub toc: Option<String>,
+    /// The same table of contents for layouts to render themselves: each
+    /// entry has a `name`, `anchor`, `depth`, `number` and `children`
+    pub toc_entries: Vec<Toc>,
     pub content: String,
+    /// The post's footnotes, if `markdown.separate_footnotes` is set and it
+    /// has any. Otherwise they're at the end of `content`.
+    pub footnotes: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -36,16 +125,25 @@ pub enum ParseError {
         #[from]
         source: ramhorns::Error,
     },
-    #[error("Unable to render markdown provided")]
+    #[error("Unable to render markdown provided. {source}")]
     CouldntRenderMarkdown {
         #[from]
         source: RenderError,
     },
-    #[error("Unable to parse markdown provided")]
+    #[error("Unable to parse markdown provided. {source}")]
     CouldntParseMarkdown {
         #[from]
         source: MarkdownError,
     },
+    #[error("Unable to read front matter. {source}")]
+    CouldntParseFrontMatter {
+        #[from]
+        source: FrontMatterError,
+    },
+    #[error("Couldn't understand the date {date:?}. Write dates as `YYYY-MM-DD`, optionally followed by a time and timezone, e.g. `2023-08-01 09:30 +0100`")]
+    InvalidDate { date: String },
+    #[error("Couldn't work out the permalink. {reason}")]
+    InvalidPermalink { reason: String },
     #[error("File not found {file}")]
     NotFound { file: String },
     #[error("Unknown Internal Error")]
@@ -57,11 +155,15 @@ impl Post {
         site_root: &Path,
         path: &Path,
         syntax_set: &SyntaxSet,
+        config: &Config,
     ) -> Result<Post, ParseError> {
         let filename = path
             .file_name()
             .and_then(|x| x.to_str())
             .map_or("".to_string(), |x| x.to_string());
+        let modified = std::fs::metadata(site_root.join(path))
+            .and_then(|metadata| metadata.modified())
+            .ok();
         let md_string = std::fs::read_to_string(site_root.join(path)).map_err(|err| {
             if err.kind() == io::ErrorKind::NotFound {
                 ParseError::NotFound {
@@ -72,7 +174,7 @@ impl Post {
             }
         })?;
 
-        let md_ast = to_mdast(&md_string, &ParseOptions::gfm())
+        let md_ast = to_mdast(&md_string, &parse_options())
             .map_err(|err| MarkdownError::ErrorParsing { wrapped: err })?;
 
         let root = match &md_ast {
@@ -83,29 +185,347 @@ impl Post {
                 })
             }
         };
-        let toc = Toc::from_mdast(root)?;
-        let content = {
+        let front_matter = FrontMatter::from_mdast(root)?;
+        let toc = Toc::from_mdast(root, front_matter.title.as_deref(), config.site.slugs)?;
+        let markdown_config = MarkdownConfig {
+            raw_html: front_matter.raw_html.unwrap_or(config.markdown.raw_html),
+            ..config.markdown.clone()
+        };
+        let (content, footnotes) = {
             let mut builder = vec![];
-            mdast_into_str_builder(&md_ast, &mut builder, syntax_set)?;
-            builder.concat()
+            let ctx = RenderContext::new(&md_ast, syntax_set, &markdown_config, config.site.slugs);
+            mdast_into_str_builder(&md_ast, &mut builder, &ctx)?;
+            let mut footnotes = vec![];
+            footnotes_into_str_builder(&mut footnotes, &ctx)?;
+            let removed = ctx.removed_html();
+            if !removed.is_empty() {
+                warn!(
+                    "Removed raw HTML from {}: {}",
+                    path.to_string_lossy(),
+                    removed.join(", ")
+                );
+            }
+            let undefined = ctx.undefined_references();
+            if !undefined.is_empty() {
+                warn!(
+                    "No definitions found in {} for references {}",
+                    path.to_string_lossy(),
+                    undefined.join(", ")
+                );
+            }
+            if markdown_config.separate_footnotes {
+                let footnotes = footnotes.concat();
+                (builder.concat(), Some(footnotes).filter(|f| !f.is_empty()))
+            } else {
+                builder.extend(footnotes);
+                (builder.concat(), None)
+            }
         };
 
-        let new_path = {
-            let mut new_path: PathBuf = path.into();
-            new_path.set_extension("html");
-            new_path.to_string_lossy().to_string()
-        };
+        let excerpt = excerpt(
+            &md_ast,
+            front_matter.excerpt.as_deref(),
+            config.index.excerpt_length,
+            syntax_set,
+            &markdown_config,
+            config.site.slugs,
+        )?;
+        let toc = toc.configure(&config.toc);
         let toc_html = toc.to_html();
-        let metadata = PostMeta {
-            title: toc.name,
-            permalink: new_path,
-            published_date: filename[0..10].to_string(),
-            excerpt: "".to_string(),
+        let slug = front_matter
+            .slug
+            .map(|slug| parameterize(&slug, config.site.slugs).into_owned())
+            .filter(|slug| !slug.is_empty())
+            .unwrap_or_else(|| {
+                parameterize(slug_from_filename(&filename), config.site.slugs).into_owned()
+            });
+        let date = match front_matter.date.as_deref().or(date_prefix(&filename)) {
+            Some(date) => Some(parse_date(date).ok_or_else(|| ParseError::InvalidDate {
+                date: date.to_owned(),
+            })?),
+            None => None,
+        };
+        let mut metadata = PostMeta {
+            title: front_matter.title.unwrap_or(toc.name),
+            permalink: String::new(),
+            published_date: date
+                .map_or_else(String::new, |date| date.format("%Y-%m-%d").to_string()),
+            date: date.map(|date| PostDate::new(date, &config.site.date_format)),
+            slug,
+            excerpt: excerpt.html,
+            excerpt_text: excerpt.text,
+            tags: front_matter
+                .tags
+                .iter()
+                .map(|tag| Tag::new(tag, config.site.slugs))
+                .collect(),
+            draft: front_matter.draft,
+            noindex: front_matter.noindex,
+            layout: front_matter.layout,
+            modified,
+            unpublished: None,
         };
+        metadata.unpublished = if metadata.draft {
+            Some("Draft".to_owned())
+        } else if metadata.is_scheduled() {
+            metadata
+                .date
+                .as_ref()
+                .map(|date| format!("Scheduled for {}", date.formatted))
+        } else {
+            None
+        };
+        let pattern = front_matter
+            .permalink
+            .as_deref()
+            .unwrap_or(&config.site.permalink);
+        let source = path.strip_prefix(site_root).unwrap_or(path);
+        metadata.permalink = expand_permalink(pattern, source, &metadata)
+            .map_err(|reason| ParseError::InvalidPermalink { reason })?;
         Ok(Post {
             metadata,
             content,
             toc: toc_html,
+            toc_entries: toc.children,
+            footnotes,
         })
     }
 }
+
+/// The placeholders permalink patterns can use.
+const PERMALINK_PLACEHOLDERS: [&str; 5] = ["year", "month", "day", "slug", "path"];
+
+/// Splits a permalink pattern into literal text and placeholder names.
+fn permalink_parts(pattern: &str) -> impl Iterator<Item = Result<&str, &str>> {
+    let mut rest = pattern;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        if let Some(after) = rest.strip_prefix(':') {
+            let end = after
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(after.len());
+            if end > 0 {
+                rest = &after[end..];
+                return Some(Err(&after[..end]));
+            }
+        }
+        let end = rest
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == ':')
+            .map_or(rest.len(), |(end, _)| end);
+        let literal = &rest[..end];
+        rest = &rest[end..];
+        Some(Ok(literal))
+    })
+}
+
+/// Checks a permalink pattern such as `/:year/:month/:slug/`, explaining
+/// what's wrong with it if it can't be used.
+pub fn validate_permalink(pattern: &str) -> Result<(), String> {
+    if !pattern.starts_with('/') {
+        return Err(format!("must start with `/`, not {pattern:?}"));
+    }
+    if !(pattern.ends_with('/') || pattern.ends_with(".html")) {
+        return Err(format!("must end with `/` or `.html`, not {pattern:?}"));
+    }
+    check_segments(pattern)?;
+    for part in permalink_parts(pattern) {
+        if let Err(name) = part {
+            if !PERMALINK_PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "uses unknown placeholder `:{name}`. Use one of :{}",
+                    PERMALINK_PLACEHOLDERS.join(", :")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a permalink can't be empty between slashes or point outside
+/// the build directory with `.` or `..`.
+fn check_segments(permalink: &str) -> Result<(), String> {
+    let segments: Vec<_> = permalink.split('/').skip(1).collect();
+    let (last, directories) = segments.split_last().unwrap_or((&"", &[]));
+    if directories.iter().any(|segment| segment.is_empty())
+        || segments
+            .iter()
+            .any(|segment| matches!(*segment, "." | ".."))
+        || last.starts_with(".html")
+    {
+        return Err(format!(
+            "can't have empty, `.` or `..` segments, as in {permalink:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// Fills in a permalink pattern for the post at `source`, relative to the
+/// site root. `:path` is the source path without its extension.
+fn expand_permalink(pattern: &str, source: &Path, post: &PostMeta) -> Result<String, String> {
+    validate_permalink(pattern)?;
+    let date = || {
+        post.datetime()
+            .ok_or_else(|| format!("{pattern:?} needs the post's date, but it doesn't have one"))
+    };
+    let mut permalink = String::new();
+    for part in permalink_parts(pattern) {
+        match part {
+            Ok(literal) => permalink.push_str(literal),
+            Err("year") => permalink += &date()?.format("%Y").to_string(),
+            Err("month") => permalink += &date()?.format("%m").to_string(),
+            Err("day") => permalink += &date()?.format("%d").to_string(),
+            Err("slug") => permalink.push_str(&post.slug),
+            Err("path") => {
+                let path = source.with_extension("");
+                let components: Vec<_> = path
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect();
+                permalink.push_str(&components.join("/"));
+            }
+            Err(_) => unreachable!("checked by validate_permalink"),
+        }
+    }
+    check_segments(&permalink)?;
+    Ok(permalink)
+}
+
+/// Strips the extension and any `YYYY-MM-DD-` date prefix from a post's filename.
+fn slug_from_filename(filename: &str) -> &str {
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    match date_prefix(stem) {
+        Some(_) => &stem[11..],
+        None => stem,
+    }
+}
+
+/// The `YYYY-MM-DD` from a filename starting `YYYY-MM-DD-`.
+fn date_prefix(filename: &str) -> Option<&str> {
+    let has_date_prefix = filename.len() > 11
+        && filename.bytes().take(11).enumerate().all(|(i, b)| {
+            matches!(i, 4 | 7 | 10) == (b == b'-') && (b == b'-' || b.is_ascii_digit())
+        });
+    has_date_prefix.then(|| &filename[..10])
+}
+
+#[cfg(test)]
+impl PostMeta {
+    /// A post called `title`, published on `date`, at `/{title}.html`.
+    pub fn dated(title: &str, date: &str) -> PostMeta {
+        PostMeta {
+            permalink: format!("/{title}.html"),
+            title: title.to_owned(),
+            published_date: date.to_owned(),
+            date: Some(PostDate::new(parse_date(date).unwrap(), "")),
+            slug: title.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl Post {
+    /// A post with no table of contents or separate footnotes.
+    pub fn with_content(metadata: PostMeta, content: &str) -> Post {
+        Post {
+            metadata,
+            toc: None,
+            toc_entries: vec![],
+            content: content.to_owned(),
+            footnotes: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permalinks() {
+        let post = PostMeta {
+            date: Some(PostDate::new(parse_date("2023-08-01").unwrap(), "")),
+            slug: "hello-world".to_owned(),
+            ..Default::default()
+        };
+        let source = Path::new("posts/2023-08-01-hello-world.md");
+        let expand = |pattern| expand_permalink(pattern, source, &post);
+        assert_eq!(
+            expand("/:year/:month/:slug/").unwrap(),
+            "/2023/08/hello-world/"
+        );
+        assert_eq!(
+            expand("/:path.html").unwrap(),
+            "/posts/2023-08-01-hello-world.html"
+        );
+        assert_eq!(expand("/about/").unwrap(), "/about/");
+        assert!(expand("/:slugg/").unwrap_err().contains("`:slugg`"));
+        assert!(validate_permalink(":slug/").is_err());
+        assert!(validate_permalink("/:slug").is_err());
+        assert!(validate_permalink("/../../escaped.html").is_err());
+        assert!(validate_permalink("/posts/./:slug/").is_err());
+        assert!(validate_permalink("/posts//:slug/").is_err());
+        assert!(validate_permalink("/:slug/..").is_err());
+        assert!(validate_permalink("/").is_ok());
+
+        let empty_slug = PostMeta {
+            slug: String::new(),
+            ..post.clone()
+        };
+        assert!(expand_permalink("/:year/:slug/", source, &empty_slug).is_err());
+
+        let undated = PostMeta::default();
+        assert!(expand_permalink("/:year/:slug/", source, &undated).is_err());
+    }
+
+    #[test]
+    fn test_error_messages_keep_their_source() {
+        let err = ParseError::from(RenderError::UndefinedReference {
+            label: "missing".to_owned(),
+        });
+        assert_eq!(
+            err.to_string(),
+            "Unable to render markdown provided. No definition found for reference [missing]"
+        );
+        let err = ParseError::from(MarkdownError::NoHeadings);
+        assert!(err
+            .to_string()
+            .ends_with("Page should begin with a level 1 heading"));
+    }
+
+    #[test]
+    fn test_skip_reason() {
+        let future = PostDate::new(parse_date("2999-01-01").unwrap(), "");
+        let draft = PostMeta {
+            draft: true,
+            ..Default::default()
+        };
+        let scheduled = PostMeta {
+            date: Some(future.clone()),
+            ..Default::default()
+        };
+        let scheduled_draft = PostMeta {
+            draft: true,
+            date: Some(future),
+            ..Default::default()
+        };
+
+        assert_eq!(PostMeta::default().skip_reason(false, false), None);
+        assert_eq!(draft.skip_reason(false, false), Some(SkipReason::Draft));
+        assert_eq!(draft.skip_reason(true, false), None);
+        assert_eq!(
+            scheduled.skip_reason(false, false),
+            Some(SkipReason::Scheduled)
+        );
+        assert_eq!(scheduled.skip_reason(false, true), None);
+        assert_eq!(
+            scheduled_draft.skip_reason(true, false),
+            Some(SkipReason::Scheduled)
+        );
+        assert_eq!(scheduled_draft.skip_reason(true, true), None);
+    }
+}
@@ -1,12 +1,18 @@
 use lazy_static::lazy_static;
-use markdown::{mdast, mdast::*};
+use markdown::{mdast, mdast::*, Constructs, ParseOptions};
+use ramhorns::Content;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use syntect::highlighting::{Theme, ThemeSet};
 use syntect::html::highlighted_html_for_string;
 use syntect::parsing::SyntaxSet;
 use thiserror::Error;
 
-use super::utils::parameterize;
+use super::config::{MarkdownConfig, TocConfig};
+use super::math::{tex_to_katex, tex_to_mathml, MathMode};
+use super::sanitise::{sanitise, RawHtml};
+use super::utils::{escape_html, is_safe_url, parameterize, SlugMode};
 
 #[derive(Error, Debug)]
 pub enum RenderError {
@@ -16,6 +22,15 @@ pub enum RenderError {
     UnknownLang { lang: String },
     #[error("Header Too Deep")]
     HeaderTooDeep,
+    #[error("No definition found for reference [{label}]")]
+    UndefinedReference { label: String },
+    #[error("Invalid maths on line {line}: {message}\n    {source_line}\n    {pointer}")]
+    InvalidMath {
+        line: usize,
+        message: String,
+        source_line: String,
+        pointer: String,
+    },
     #[error("Rhodium doesn't currently support {node_type}.")]
     NodeNotSupported { node_type: &'static str },
     #[error("Internal Error: md ast nodes have been structured in an unexpected way.")]
@@ -55,10 +70,15 @@ pub enum MarkdownError {
     ManyTitles { second_title: String },
 }
 
-#[derive(Debug)]
+/// A heading and the headings in its section. The root is the page title.
+#[derive(Content, Debug)]
 pub struct Toc {
     pub depth: u8,
     pub name: String,
+    /// The id of the heading, as given by [`heading_ids`]
+    pub anchor: String,
+    /// The section number, e.g. `2.1`, if `toc.numbered` is set
+    pub number: Option<String>,
     pub children: Vec<Toc>,
 }
 
@@ -77,9 +97,14 @@ impl Toc {
         builder.push(Cow::Borrowed("<ol>"));
         for child in &self.children {
             builder.push(Cow::Borrowed("<li><a href=\"#"));
-            builder.push(parameterize(&child.name));
+            builder.push(escape_html(&child.anchor));
             builder.push(Cow::Borrowed("\">"));
-            builder.push(Cow::Borrowed(&child.name));
+            if let Some(number) = &child.number {
+                builder.push(Cow::Borrowed("<span class=\"toc-number\">"));
+                builder.push(Cow::Borrowed(number));
+                builder.push(Cow::Borrowed("</span> "));
+            }
+            builder.push(escape_html(&child.name));
             builder.push(Cow::Borrowed("</a></li>"));
             if !child.children.is_empty() {
                 child.child_html_builder(builder);
@@ -88,37 +113,100 @@ impl Toc {
         builder.push(Cow::Borrowed("</ol>"));
     }
 
-    pub fn from_mdast(root: &mdast::Root) -> Result<Self, MarkdownError> {
-        let mut headings = root.children.iter().filter_map(|node| {
-            if let mdast::Node::Heading(heading) = node {
-                Some((node, heading))
-            } else {
-                None
+    /// Applies `toc.min_depth`, `toc.max_depth` and `toc.numbered` to the
+    /// entries below the title.
+    pub fn configure(mut self, config: &TocConfig) -> Self {
+        fn limit(entries: Vec<Toc>, config: &TocConfig) -> Vec<Toc> {
+            entries
+                .into_iter()
+                .flat_map(|mut entry| {
+                    entry.children = limit(entry.children, config);
+                    if entry.depth < config.min_depth {
+                        entry.children
+                    } else if entry.depth > config.max_depth {
+                        vec![]
+                    } else {
+                        vec![entry]
+                    }
+                })
+                .collect()
+        }
+        fn number_entries(entries: &mut [Toc], prefix: &str) {
+            for (i, entry) in entries.iter_mut().enumerate() {
+                let number = format!("{prefix}{}", i + 1);
+                number_entries(&mut entry.children, &format!("{number}."));
+                entry.number = Some(number);
+            }
+        }
+
+        self.children = limit(self.children, config);
+        if config.numbered {
+            number_entries(&mut self.children, "");
+        }
+        self
+    }
+
+    /// Builds the table of contents from the document's headings. The first
+    /// heading must be the page title unless `fallback_title` is given (e.g.
+    /// from front matter), in which case it becomes the root instead.
+    pub fn from_mdast(
+        root: &mdast::Root,
+        fallback_title: Option<&str>,
+        slugs: SlugMode,
+    ) -> Result<Self, MarkdownError> {
+        let ids = heading_ids(&root.children, slugs);
+        let mut headings = root
+            .children
+            .iter()
+            .filter_map(|node| {
+                if let mdast::Node::Heading(heading) = node {
+                    Some(heading)
+                } else {
+                    None
+                }
+            })
+            .peekable();
+
+        let first_is_title = matches!(headings.peek(), Some(title) if title.depth == 1);
+        let root_toc = match fallback_title {
+            Some(name) if !first_is_title => Self {
+                depth: 1,
+                children: vec![],
+                name: name.to_owned(),
+                anchor: String::new(),
+                number: None,
+            },
+            _ => {
+                let title = headings.next().ok_or(MarkdownError::NoHeadings)?;
+                if title.depth != 1 {
+                    return Err(MarkdownError::FirstHeadingNotTitle);
+                };
+                Self {
+                    depth: title.depth,
+                    children: vec![],
+                    name: heading_text(title),
+                    anchor: heading_id(&ids, title, slugs).into_owned(),
+                    number: None,
+                }
             }
-        });
-        let (title_node, title) = headings.next().ok_or(MarkdownError::NoHeadings)?;
-        if title.depth != 1 {
-            return Err(MarkdownError::FirstHeadingNotTitle);
         };
 
-        let mut stack: Vec<Self> = vec![Self {
-            depth: title.depth,
-            children: vec![],
-            name: title_node.to_string(),
-        }];
+        let mut stack: Vec<Self> = vec![root_toc];
 
-        for (head_node, head) in headings {
+        for head in headings {
             while stack.last().unwrap().depth >= head.depth {
                 let child = stack.pop().unwrap();
                 let parent = stack.last_mut().ok_or_else(|| MarkdownError::ManyTitles {
-                    second_title: head_node.to_string(),
+                    second_title: heading_text(head),
                 })?;
                 parent.children.push(child);
             }
             stack.push(Self {
                 depth: head.depth,
                 children: vec![],
-                name: head_node.to_string(),
+                name: heading_text(head),
+                anchor: heading_id(&ids, head, slugs).into_owned(),
+                number: None,
             });
         }
         while stack.len() > 1 {
@@ -130,33 +218,631 @@ impl Toc {
     }
 }
 
+/// The id given explicitly at the end of a heading, as in
+/// `## Title {#custom-id}`, and the length of the heading's last text node
+/// without it.
+fn explicit_id(heading: &Heading) -> Option<(&str, usize)> {
+    let Some(Node::Text(Text { value, .. })) = heading.children.last() else {
+        return None;
+    };
+    let inner = value.trim_end().strip_suffix('}')?;
+    let start = inner.rfind("{#")?;
+    let id = &inner[start + 2..];
+    let valid = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.');
+    if id.is_empty() || !id.chars().all(valid) {
+        return None;
+    }
+    Some((id, inner[..start].trim_end().len()))
+}
+
+/// The plain text of a heading, without any explicit id.
+fn heading_text(heading: &Heading) -> String {
+    let mut text: String = heading.children.iter().map(Node::to_string).collect();
+    if let (Some((_, len)), Some(Node::Text(Text { value, .. }))) =
+        (explicit_id(heading), heading.children.last())
+    {
+        text.truncate(text.len() - (value.len() - len));
+    }
+    text
+}
+
+/// The labels of anything in `text` written like a full (`[text][label]`) or
+/// collapsed (`[label][]`) reference. Text that starts `][label]` is taken to
+/// close link text opened before some markup, as in `[*text*][label]`.
+fn reference_labels(text: &str) -> Vec<&str> {
+    let mut labels = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("][") {
+        let before = &rest[..start];
+        let after = &rest[start + 2..];
+        let Some(end) = after.find(']') else {
+            break;
+        };
+        let link = link_text(before);
+        let continues_link = before.is_empty() && rest.len() == text.len();
+        let label = match &after[..end] {
+            _ if link.is_none() && !continues_link => None,
+            "" => link,
+            label if label.contains('[') => None,
+            label => Some(label),
+        };
+        labels.extend(label.filter(|label| !label.trim().is_empty()));
+        rest = &after[end + 1..];
+    }
+    labels
+}
+
+/// The bracketed text `before` ends with, if it could be a link's text:
+/// its brackets balance and the opening one doesn't follow a word, as in
+/// `m[i]`.
+fn link_text(before: &str) -> Option<&str> {
+    let mut depth = 0;
+    for (i, c) in before.char_indices().rev() {
+        match c {
+            ']' => depth += 1,
+            '[' if depth > 0 => depth -= 1,
+            '[' => {
+                let follows_word = before[..i].ends_with(char::is_alphanumeric);
+                return (!follows_word).then(|| &before[i + 1..]);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether `id` is one of the `fn-N`, `fnref-N` or `fnref-N-K` ids given to
+/// footnotes and their references.
+fn is_footnote_id(id: &str) -> bool {
+    let is_number = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+    if let Some(number) = id.strip_prefix("fn-") {
+        return is_number(number);
+    }
+    id.strip_prefix("fnref-").is_some_and(|rest| {
+        let (number, reference) = rest.split_once('-').unwrap_or((rest, "1"));
+        is_number(number) && is_number(reference)
+    })
+}
+
+/// Where `heading` starts in the source, which [`heading_ids`] are keyed by.
+/// Unlike its address, this survives the node being cloned.
+fn heading_key(heading: &Heading) -> Option<usize> {
+    heading
+        .position
+        .as_ref()
+        .map(|position| position.start.offset)
+}
+
+/// The id [`heading_ids`] gave `heading`. Headings it doesn't know of, such
+/// as ones built rather than parsed, are named after their id or text.
+fn heading_id<'a>(
+    ids: &'a HashMap<usize, String>,
+    heading: &Heading,
+    slugs: SlugMode,
+) -> Cow<'a, str> {
+    match heading_key(heading).and_then(|key| ids.get(&key)) {
+        Some(id) => Cow::Borrowed(id),
+        None => match explicit_id(heading) {
+            Some((id, _)) => Cow::Owned(id.to_owned()),
+            None => Cow::Owned(parameterize(&heading_text(heading), slugs).into_owned()),
+        },
+    }
+}
+
+/// Assigns every heading in the document a unique id, keyed by where the
+/// heading starts in the source. An explicit `{#id}` is used as given by the first heading with
+/// it. Other headings are named after their id or text, with `-1`, `-2`...
+/// added to repeated names. Footnotes' ids are never used.
+pub fn heading_ids(nodes: &[Node], slugs: SlugMode) -> HashMap<usize, String> {
+    fn collect<'a>(nodes: &'a [Node], headings: &mut Vec<&'a Heading>) {
+        for node in nodes {
+            if let Node::Heading(heading) = node {
+                headings.push(heading);
+            }
+            collect(node.children().map_or(&[], Vec::as_slice), headings);
+        }
+    }
+    let mut headings = vec![];
+    collect(nodes, &mut headings);
+
+    // Explicit ids are claimed first so generated ones can't take them
+    let mut claimed: HashMap<&str, usize> = HashMap::new();
+    for (i, heading) in headings.iter().enumerate() {
+        if let Some((id, _)) = explicit_id(heading).filter(|(id, _)| !is_footnote_id(id)) {
+            claimed.entry(id).or_insert(i);
+        }
+    }
+    let mut used: HashSet<String> = claimed.keys().map(|id| (*id).to_owned()).collect();
+    let mut ids = HashMap::with_capacity(headings.len());
+    for (i, heading) in headings.into_iter().enumerate() {
+        let Some(key) = heading_key(heading) else {
+            continue;
+        };
+        let base = match explicit_id(heading) {
+            Some((id, _)) if claimed.get(id) == Some(&i) => {
+                ids.insert(key, id.to_owned());
+                continue;
+            }
+            Some((id, _)) => Cow::Borrowed(id),
+            None => match parameterize(&heading_text(heading), slugs) {
+                base if base.is_empty() => Cow::Borrowed("section"),
+                base => Cow::Owned(base.into_owned()),
+            },
+        };
+        let base = if is_footnote_id(&base) {
+            Cow::Owned(format!("section-{base}"))
+        } else {
+            base
+        };
+        let mut id = base.to_string();
+        let mut n = 0;
+        while used.contains(&id) {
+            n += 1;
+            id = format!("{base}-{n}");
+        }
+        used.insert(id.clone());
+        ids.insert(key, id);
+    }
+    ids
+}
+
+/// GFM plus front matter and `$`/`$$` maths.
+pub fn parse_options() -> ParseOptions {
+    ParseOptions {
+        constructs: Constructs {
+            frontmatter: true,
+            math_flow: true,
+            math_text: true,
+            ..Constructs::gfm()
+        },
+        ..ParseOptions::gfm()
+    }
+}
+
+/// Per-document state needed while rendering, such as the link and image
+/// definitions that references resolve against.
+pub struct RenderContext<'a> {
+    syntax_set: &'a SyntaxSet,
+    config: &'a MarkdownConfig,
+    definitions: HashMap<&'a str, &'a Definition>,
+    footnote_definitions: HashMap<&'a str, &'a FootnoteDefinition>,
+    heading_ids: HashMap<usize, String>,
+    slugs: SlugMode,
+    /// The footnotes referenced so far, in order of first reference, with how
+    /// many times each has been referenced
+    footnotes: RefCell<Vec<(&'a str, usize)>>,
+    /// What the raw HTML policy has taken out of the page so far
+    removed_html: RefCell<Vec<String>>,
+    /// The element raw HTML is being dropped inside of, if its closing tag
+    /// is in a later node
+    dropping_html: RefCell<Option<String>>,
+    /// Labels of references left as text because nothing defines them
+    undefined_references: RefCell<Vec<String>>,
+    /// Whether this is an excerpt, shown away from the rest of the post.
+    /// Excerpts leave out footnote references and heading ids, which would
+    /// point nowhere or repeat on listing pages.
+    excerpt: bool,
+}
+
+impl<'a> RenderContext<'a> {
+    pub fn new(
+        root: &'a mdast::Node,
+        syntax_set: &'a SyntaxSet,
+        config: &'a MarkdownConfig,
+        slugs: SlugMode,
+    ) -> Self {
+        fn collect<'a>(node: &'a mdast::Node, ctx: &mut RenderContext<'a>) {
+            // As in CommonMark, the first definition of a label wins
+            match node {
+                Node::Definition(definition) => {
+                    ctx.definitions
+                        .entry(definition.identifier.as_str())
+                        .or_insert(definition);
+                }
+                Node::FootnoteDefinition(definition) => {
+                    ctx.footnote_definitions
+                        .entry(definition.identifier.as_str())
+                        .or_insert(definition);
+                }
+                _ => {}
+            }
+            for child in node.children().into_iter().flatten() {
+                collect(child, ctx);
+            }
+        }
+
+        let mut ctx = RenderContext {
+            syntax_set,
+            config,
+            definitions: HashMap::new(),
+            footnote_definitions: HashMap::new(),
+            heading_ids: heading_ids(std::slice::from_ref(root), slugs),
+            slugs,
+            footnotes: RefCell::new(vec![]),
+            removed_html: RefCell::new(vec![]),
+            undefined_references: RefCell::new(vec![]),
+            dropping_html: RefCell::new(None),
+            excerpt: false,
+        };
+        collect(root, &mut ctx);
+        ctx
+    }
+
+    /// A context for rendering part of `root` as an excerpt.
+    pub fn excerpt(
+        root: &'a mdast::Node,
+        syntax_set: &'a SyntaxSet,
+        config: &'a MarkdownConfig,
+        slugs: SlugMode,
+    ) -> Self {
+        RenderContext {
+            excerpt: true,
+            ..RenderContext::new(root, syntax_set, config, slugs)
+        }
+    }
+
+    /// Descriptions of the raw HTML removed while rendering, per
+    /// `markdown.raw_html`.
+    pub fn removed_html(&self) -> Vec<String> {
+        self.removed_html.take()
+    }
+
+    /// The labels of `[text][label]` references that were rendered as plain
+    /// text because nothing defines them.
+    pub fn undefined_references(&self) -> Vec<String> {
+        self.undefined_references.take()
+    }
+
+    /// Notes any undefined references in `text`. The parser leaves those as
+    /// text rather than producing a `LinkReference`.
+    fn check_references(&self, text: &str) {
+        for label in reference_labels(text) {
+            let identifier = label
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase();
+            if !self.definitions.contains_key(identifier.as_str()) {
+                let mut undefined = self.undefined_references.borrow_mut();
+                let label = format!("[{label}]");
+                if !undefined.contains(&label) {
+                    undefined.push(label);
+                }
+            }
+        }
+    }
+
+    /// Records a reference to a footnote, returning the footnote's number and
+    /// how many times it has now been referenced.
+    fn reference_footnote(
+        &self,
+        identifier: &str,
+        label: Option<&str>,
+    ) -> Result<(usize, usize), RenderError> {
+        let (identifier, _) = self
+            .footnote_definitions
+            .get_key_value(identifier)
+            .ok_or_else(|| RenderError::UndefinedReference {
+                label: format!("^{}", label.unwrap_or(identifier)),
+            })?;
+        let mut footnotes = self.footnotes.borrow_mut();
+        let index = match footnotes.iter().position(|(id, _)| id == identifier) {
+            Some(index) => index,
+            None => {
+                footnotes.push((identifier, 0));
+                footnotes.len() - 1
+            }
+        };
+        footnotes[index].1 += 1;
+        Ok((index + 1, footnotes[index].1))
+    }
+
+    /// Looks up the definition a reference points at. Identifiers are already
+    /// normalised by the parser, so this matches labels case-insensitively.
+    fn definition(
+        &self,
+        identifier: &str,
+        label: Option<&str>,
+    ) -> Result<&'a Definition, RenderError> {
+        self.definitions
+            .get(identifier)
+            .copied()
+            .ok_or_else(|| RenderError::UndefinedReference {
+                label: label.unwrap_or(identifier).to_owned(),
+            })
+    }
+}
+
+/// The id of the `reference`th reference to footnote `number`.
+fn footnote_reference_id(number: usize, reference: usize) -> String {
+    if reference == 1 {
+        format!("fnref-{number}")
+    } else {
+        format!("fnref-{number}-{reference}")
+    }
+}
+
+/// Renders the footnotes referenced while rendering the page, in order of
+/// first reference, each linking back to every reference to it. Renders
+/// nothing if there weren't any.
+pub fn footnotes_into_str_builder<'a>(
+    builder: &mut Vec<Cow<'a, str>>,
+    ctx: &RenderContext<'a>,
+) -> Result<(), RenderError> {
+    if ctx.footnotes.borrow().is_empty() {
+        return Ok(());
+    }
+    builder.push(Cow::Borrowed("<section class=\"footnotes\"><ol>"));
+    // Footnotes can reference footnotes, so this list can grow as it's rendered
+    let mut index = 0;
+    loop {
+        let Some(&(identifier, _)) = ctx.footnotes.borrow().get(index) else {
+            break;
+        };
+        let number = index + 1;
+        let children = &ctx.footnote_definitions[identifier].children;
+        builder.push(Cow::Owned(format!("<li id=\"fn-{number}\">")));
+        let (last, rest) = match children.split_last() {
+            Some((Node::Paragraph(last), rest)) => (Some(last), rest),
+            _ => (None, &children[..]),
+        };
+        for child in rest {
+            mdast_into_str_builder(child, builder, ctx)?;
+        }
+        if let Some(last) = last {
+            builder.push(Cow::Borrowed("<p>"));
+            for child in &last.children {
+                mdast_into_str_builder(child, builder, ctx)?;
+            }
+        }
+        // Read the count only now, after any references to this footnote
+        // from within itself
+        let references = ctx.footnotes.borrow()[index].1;
+        for reference in 1..=references {
+            builder.push(Cow::Owned(format!(
+                " <a href=\"#{}\" class=\"footnote-backref\">↩{}</a>",
+                footnote_reference_id(number, reference),
+                if reference == 1 {
+                    String::new()
+                } else {
+                    format!("<sup>{reference}</sup>")
+                }
+            )));
+        }
+        if last.is_some() {
+            builder.push(Cow::Borrowed("</p>"));
+        }
+        builder.push(Cow::Borrowed("</li>"));
+        index += 1;
+    }
+    builder.push(Cow::Borrowed("</ol></section>"));
+    Ok(())
+}
+
+/// Renders a list item. Paragraphs in the items of tight lists aren't
+/// wrapped in `<p>`, and GFM task items start with a disabled checkbox.
+fn list_item_into_str_builder<'a>(
+    item: &'a ListItem,
+    loose: bool,
+    builder: &mut Vec<Cow<'a, str>>,
+    ctx: &RenderContext<'a>,
+) -> Result<(), RenderError> {
+    let mut checkbox = item.checked.map(|checked| {
+        Cow::Borrowed(if checked {
+            "<input type=\"checkbox\" disabled=\"\" checked=\"\" /> "
+        } else {
+            "<input type=\"checkbox\" disabled=\"\" /> "
+        })
+    });
+    builder.push(Cow::Borrowed(if checkbox.is_some() {
+        "<li class=\"task-list-item\">"
+    } else {
+        "<li>"
+    }));
+    for (i, child) in item.children.iter().enumerate() {
+        match child {
+            Node::Paragraph(Paragraph { children, .. }) if !loose => {
+                if i > 0 {
+                    builder.push(Cow::Borrowed("\n"));
+                }
+                builder.extend(checkbox.take());
+                for child in children {
+                    mdast_into_str_builder(child, builder, ctx)?;
+                }
+            }
+            Node::Paragraph(Paragraph { children, .. }) if checkbox.is_some() => {
+                builder.push(Cow::Borrowed("<p>"));
+                builder.extend(checkbox.take());
+                for child in children {
+                    mdast_into_str_builder(child, builder, ctx)?;
+                }
+                builder.push(Cow::Borrowed("</p>"));
+            }
+            _ => {
+                builder.extend(checkbox.take());
+                mdast_into_str_builder(child, builder, ctx)?;
+            }
+        }
+    }
+    builder.extend(checkbox);
+    builder.push(Cow::Borrowed("</li>"));
+    Ok(())
+}
+
+/// Escapes a URL for an `href` or `src` attribute, replacing it with an
+/// empty one if it could run script.
+fn url_attribute(url: &str) -> Cow<'_, str> {
+    if is_safe_url(url) {
+        escape_html(url)
+    } else {
+        Cow::Borrowed("")
+    }
+}
+
+fn link_into_str_builder<'a>(
+    builder: &mut Vec<Cow<'a, str>>,
+    url: &'a str,
+    title: Option<&'a str>,
+    children: &'a [Node],
+    ctx: &RenderContext<'a>,
+) -> Result<(), RenderError> {
+    builder.push(Cow::Borrowed("<a href=\""));
+    builder.push(url_attribute(url));
+    if let Some(title) = title {
+        builder.push(Cow::Borrowed("\" title=\""));
+        builder.push(escape_html(title));
+    }
+    builder.push(Cow::Borrowed("\">"));
+    for child in children {
+        mdast_into_str_builder(child, builder, ctx)?;
+    }
+    builder.push(Cow::Borrowed("</a>"));
+    Ok(())
+}
+
+fn image_into_str_builder<'a>(
+    builder: &mut Vec<Cow<'a, str>>,
+    url: &'a str,
+    alt: &'a str,
+    title: Option<&'a str>,
+) {
+    builder.push(Cow::Borrowed("<img src=\""));
+    builder.push(url_attribute(url));
+    builder.push(Cow::Borrowed("\" alt=\""));
+    builder.push(escape_html(alt));
+    if let Some(title) = title {
+        builder.push(Cow::Borrowed("\" title=\""));
+        builder.push(escape_html(title));
+    }
+    builder.push(Cow::Borrowed("\" />"));
+}
+
+/// An image with a title on a line of its own is rendered as a figure, with
+/// the title as its caption.
+fn figure_into_str_builder<'a>(
+    node: &'a mdast::Node,
+    builder: &mut Vec<Cow<'a, str>>,
+    ctx: &RenderContext<'a>,
+) -> Result<bool, RenderError> {
+    let (url, alt, title) = match node {
+        Node::Image(Image {
+            url,
+            alt,
+            title: Some(title),
+            ..
+        }) => (url, alt, title),
+        Node::ImageReference(ImageReference {
+            identifier,
+            alt,
+            label,
+            ..
+        }) => match ctx.definition(identifier, label.as_deref())? {
+            Definition {
+                url,
+                title: Some(title),
+                ..
+            } => (url, alt, title),
+            _ => return Ok(false),
+        },
+        _ => return Ok(false),
+    };
+
+    builder.push(Cow::Borrowed("<figure>"));
+    image_into_str_builder(builder, url, alt, None);
+    builder.push(Cow::Borrowed("<figcaption>"));
+    builder.push(escape_html(title));
+    builder.push(Cow::Borrowed("</figcaption></figure>"));
+    Ok(true)
+}
+
+fn math_to_html(
+    tex: &str,
+    display: bool,
+    position: &Option<markdown::unist::Position>,
+    ctx: &RenderContext,
+) -> Result<String, RenderError> {
+    if ctx.config.math == MathMode::Katex {
+        return Ok(tex_to_katex(tex, display));
+    }
+
+    tex_to_mathml(tex, display).map_err(|err| {
+        let line_start = tex[..err.offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = tex[err.offset..]
+            .find('\n')
+            .map_or(tex.len(), |i| err.offset + i);
+        // Display maths starts on the line after the opening `$$`
+        let first_line = position.as_ref().map_or(1, |p| p.start.line) + usize::from(display);
+        RenderError::InvalidMath {
+            line: first_line + tex[..line_start].matches('\n').count(),
+            message: err.message,
+            source_line: tex[line_start..line_end].to_owned(),
+            pointer: format!(
+                "{}^",
+                " ".repeat(tex[line_start..err.offset].chars().count())
+            ),
+        }
+    })
+}
+
 const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
 pub fn mdast_into_str_builder<'a>(
     node: &'a mdast::Node,
     builder: &mut Vec<std::borrow::Cow<'a, str>>,
-    syntax_set: &SyntaxSet,
+    ctx: &RenderContext<'a>,
 ) -> Result<(), RenderError> {
+    if ctx.dropping_html.borrow().is_some() {
+        match node {
+            Node::Html(_) => {}
+            Node::Text(_)
+            | Node::InlineCode(_)
+            | Node::InlineMath(_)
+            | Node::Emphasis(_)
+            | Node::Strong(_)
+            | Node::Delete(_)
+            | Node::Link(_)
+            | Node::LinkReference(_)
+            | Node::Image(_)
+            | Node::ImageReference(_)
+            | Node::Break(_)
+            | Node::FootnoteReference(_) => return Ok(()),
+            // An element opened inline can't carry on past its paragraph
+            _ => *ctx.dropping_html.borrow_mut() = None,
+        }
+    }
     match node {
         Node::Root(Root { children, .. }) => {
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                mdast_into_str_builder(child, builder, ctx)?;
             }
             Ok(())
         }
-        Node::Text(Text { value, .. }) | Node::Html(Html { value, .. }) => {
-            builder.push(Cow::Borrowed(value));
+        Node::Text(Text { value, .. }) => {
+            ctx.check_references(value);
+            builder.push(escape_html(value));
+            Ok(())
+        }
+        Node::Html(Html { value, .. }) => {
+            match ctx.config.raw_html {
+                RawHtml::Allow => builder.push(Cow::Borrowed(value)),
+                policy => builder.push(Cow::Owned(sanitise(
+                    value,
+                    policy,
+                    &mut ctx.removed_html.borrow_mut(),
+                    &mut ctx.dropping_html.borrow_mut(),
+                ))),
+            }
             Ok(())
         }
         Node::InlineCode(InlineCode { value, .. }) => {
             builder.push(Cow::Borrowed("<code>"));
-            builder.push(Cow::Borrowed(value));
+            builder.push(escape_html(value));
             builder.push(Cow::Borrowed("</code>"));
             Ok(())
         }
         Node::Emphasis(Emphasis { children, .. }) => {
             builder.push(Cow::Borrowed("<em>"));
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                mdast_into_str_builder(child, builder, ctx)?;
             }
             builder.push(Cow::Borrowed("</em>"));
             Ok(())
@@ -164,7 +850,7 @@ pub fn mdast_into_str_builder<'a>(
         Node::Strong(Strong { children, .. }) => {
             builder.push(Cow::Borrowed("<strong>"));
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                mdast_into_str_builder(child, builder, ctx)?;
             }
             builder.push(Cow::Borrowed("</strong>"));
             Ok(())
@@ -176,7 +862,7 @@ pub fn mdast_into_str_builder<'a>(
         Node::Delete(Delete { children, .. }) => {
             builder.push(Cow::Borrowed("<del>"));
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                mdast_into_str_builder(child, builder, ctx)?;
             }
             builder.push(Cow::Borrowed("</del>"));
             Ok(())
@@ -186,23 +872,19 @@ pub fn mdast_into_str_builder<'a>(
             url,
             title,
             ..
+        }) => link_into_str_builder(builder, url, title.as_deref(), children, ctx),
+        Node::LinkReference(LinkReference {
+            children,
+            identifier,
+            label,
+            ..
         }) => {
-            builder.push(Cow::Borrowed("<a href=\""));
-            builder.push(Cow::Borrowed(url));
-            if let Some(title) = title {
-                builder.push(Cow::Borrowed("\" title=\""));
-                builder.push(Cow::Borrowed(title));
-            }
-            builder.push(Cow::Borrowed("\">"));
-            for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
-            }
-            builder.push(Cow::Borrowed("</a>"));
-            Ok(())
+            let Definition { url, title, .. } = ctx.definition(identifier, label.as_deref())?;
+            link_into_str_builder(builder, url, title.as_deref(), children, ctx)
         }
         Node::Code(Code { value, lang, .. }) => {
             if let Some(lang) = lang {
-                match highlight_fragment(value, lang, syntax_set) {
+                match highlight_fragment(value, lang, ctx.syntax_set) {
                     Ok(highlighted) => {
                         builder.push(Cow::Owned(highlighted));
                         return Ok(());
@@ -216,31 +898,61 @@ pub fn mdast_into_str_builder<'a>(
             builder.push(Cow::Borrowed(
                 "<pre style=\"background-color:#2b303b;\"><code>",
             ));
-            builder.push(Cow::Borrowed(value));
+            builder.push(escape_html(value));
             builder.push(Cow::Borrowed("</code></pre>"));
             Ok(())
         }
         Node::Paragraph(Paragraph { children, .. }) => {
+            if let [only_child] = &children[..] {
+                if figure_into_str_builder(only_child, builder, ctx)? {
+                    return Ok(());
+                }
+            }
             builder.push(Cow::Borrowed("<p>"));
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                mdast_into_str_builder(child, builder, ctx)?;
             }
             builder.push(Cow::Borrowed("</p>"));
             Ok(())
         }
-        Node::List(List { children, .. }) => {
-            builder.push(Cow::Borrowed("<ol>"));
+        Node::List(List {
+            children,
+            ordered,
+            start,
+            spread,
+            ..
+        }) => {
+            let tag = if *ordered { "ol" } else { "ul" };
+            builder.push(Cow::Borrowed("<"));
+            builder.push(Cow::Borrowed(tag));
+            match start {
+                Some(start) if *ordered && *start != 1 => {
+                    builder.push(Cow::Owned(format!(" start=\"{start}\"")));
+                }
+                _ => {}
+            }
+            builder.push(Cow::Borrowed(">"));
+            // As in CommonMark, a blank line anywhere makes the whole list loose
+            let loose = *spread
+                || children
+                    .iter()
+                    .any(|child| matches!(child, Node::ListItem(ListItem { spread: true, .. })));
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                match child {
+                    Node::ListItem(item) => list_item_into_str_builder(item, loose, builder, ctx)?,
+                    _ => mdast_into_str_builder(child, builder, ctx)?,
+                }
             }
-            builder.push(Cow::Borrowed("</ol>"));
+            builder.push(Cow::Borrowed("</"));
+            builder.push(Cow::Borrowed(tag));
+            builder.push(Cow::Borrowed(">"));
             Ok(())
         }
 
         Node::BlockQuote(BlockQuote { children, .. }) => {
             builder.push(Cow::Borrowed("<blockquote>"));
             for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+                mdast_into_str_builder(child, builder, ctx)?;
             }
             builder.push(Cow::Borrowed("</blockquote>"));
             Ok(())
@@ -263,7 +975,7 @@ pub fn mdast_into_str_builder<'a>(
                     }
                     if let Node::TableCell(TableCell { children: cell, .. }) = head {
                         for node in cell {
-                            mdast_into_str_builder(node, builder, syntax_set)?;
+                            mdast_into_str_builder(node, builder, ctx)?;
                         }
                     } else {
                         return Err(RenderError::InternalError);
@@ -289,7 +1001,7 @@ pub fn mdast_into_str_builder<'a>(
                             }
                             if let Node::TableCell(TableCell { children: cell, .. }) = cell {
                                 for node in cell {
-                                    mdast_into_str_builder(node, builder, syntax_set)?;
+                                    mdast_into_str_builder(node, builder, ctx)?;
                                 }
                             } else {
                                 return Err(RenderError::InternalError);
@@ -309,96 +1021,409 @@ pub fn mdast_into_str_builder<'a>(
 
         Node::TableRow(_) | Node::TableCell(_) => Err(RenderError::InternalError),
 
-        Node::ListItem(ListItem { children, .. }) => {
-            builder.push(Cow::Borrowed("<li>"));
-            for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
-            }
-            builder.push(Cow::Borrowed("</li>"));
-            Ok(())
-        }
-        Node::Heading(Heading {
-            depth, children, ..
-        }) => {
-            let heading = HEADINGS
+        Node::ListItem(item) => list_item_into_str_builder(item, item.spread, builder, ctx),
+        Node::Heading(
+            heading @ Heading {
+                depth, children, ..
+            },
+        ) => {
+            let tag = HEADINGS
                 .get((*depth - 1) as usize)
                 .ok_or(RenderError::HeaderTooDeep)?;
-            let name = node.to_string();
             builder.push(Cow::Borrowed("<"));
-            builder.push(Cow::Borrowed(heading));
-            builder.push(Cow::Borrowed(" id=\""));
-            builder.push(Cow::Owned(parameterize(&name).into_owned()));
-            builder.push(Cow::Borrowed("\">"));
-            for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
+            builder.push(Cow::Borrowed(tag));
+            if !ctx.excerpt {
+                builder.push(Cow::Borrowed(" id=\""));
+                let id = heading_id(&ctx.heading_ids, heading, ctx.slugs);
+                builder.push(Cow::Owned(escape_html(&id).into_owned()));
+                builder.push(Cow::Borrowed("\""));
+            }
+            builder.push(Cow::Borrowed(">"));
+            match (explicit_id(heading), children.split_last()) {
+                (Some((_, len)), Some((Node::Text(Text { value, .. }), rest))) => {
+                    for child in rest {
+                        mdast_into_str_builder(child, builder, ctx)?;
+                    }
+                    builder.push(escape_html(&value[..len]));
+                }
+                _ => {
+                    for child in children {
+                        mdast_into_str_builder(child, builder, ctx)?;
+                    }
+                }
             }
             builder.push(Cow::Borrowed("</"));
-            builder.push(Cow::Borrowed(heading));
+            builder.push(Cow::Borrowed(tag));
             builder.push(Cow::Borrowed(">"));
             Ok(())
         }
-        Node::ThematicBreak(ThematicBreak { .. }) => {
-            builder.push(Cow::Borrowed("<hr />"));
-            Ok(())
-        }
-        Node::FootnoteReference(FootnoteReference {
-            identifier, label, ..
+        Node::Image(Image {
+            url, alt, title, ..
         }) => {
-            if let Some(label) = label {
-                builder.push(Cow::Borrowed("<sup><a href=\"#"));
-                builder.push(parameterize(label));
-                builder.push(Cow::Borrowed("\">"));
-                builder.push(Cow::Borrowed(identifier));
-                builder.push(Cow::Borrowed("</a></sup>"));
-            } else {
-                builder.push(Cow::Borrowed(identifier));
-            }
+            image_into_str_builder(builder, url, alt, title.as_deref());
             Ok(())
         }
-        Node::FootnoteDefinition(FootnoteDefinition {
-            children,
+        Node::ImageReference(ImageReference {
             identifier,
+            alt,
             label,
             ..
         }) => {
-            builder.push(Cow::Borrowed("<div class=\"footnote-definition\" id=\""));
-            builder.push(Cow::Borrowed(identifier));
-            builder.push(Cow::Borrowed(
-                "\"><div class=\"footnote-definition-label\">",
-            ));
-            builder.push(Cow::Borrowed(label.as_ref().unwrap_or(identifier)));
-            builder.push(Cow::Borrowed("</div>"));
-            for child in children {
-                mdast_into_str_builder(child, builder, syntax_set)?;
-            }
-            builder.push(Cow::Borrowed("</div>"));
+            let Definition { url, title, .. } = ctx.definition(identifier, label.as_deref())?;
+            image_into_str_builder(builder, url, alt, title.as_deref());
             Ok(())
         }
+        Node::Math(Math {
+            value, position, ..
+        }) => {
+            builder.push(Cow::Owned(math_to_html(value, true, position, ctx)?));
+            Ok(())
+        }
+        Node::InlineMath(InlineMath { value, position }) => {
+            builder.push(Cow::Owned(math_to_html(value, false, position, ctx)?));
+            Ok(())
+        }
+        Node::Definition(_) => {
+            // Definitions are resolved through RenderContext and have no output of their own
+            Ok(())
+        }
+        Node::ThematicBreak(ThematicBreak { .. }) => {
+            builder.push(Cow::Borrowed("<hr />"));
+            Ok(())
+        }
+        Node::FootnoteReference(_) if ctx.excerpt => Ok(()),
+        Node::FootnoteReference(FootnoteReference {
+            identifier, label, ..
+        }) => {
+            let (number, reference) = ctx.reference_footnote(identifier, label.as_deref())?;
+            builder.push(Cow::Owned(format!(
+                "<sup class=\"footnote-ref\"><a href=\"#fn-{number}\" id=\"{}\">{number}</a></sup>",
+                footnote_reference_id(number, reference)
+            )));
+            Ok(())
+        }
+        // Collected at the end of the page by footnotes_into_str_builder
+        Node::FootnoteDefinition(_) => Ok(()),
 
         // Errors
         Node::Toml(_) | Node::Yaml(_) => {
-            // Ignore frontmatter. Where we're going we ain't going to need it.
+            // Frontmatter is read into PostMeta by Post::from_file, not rendered.
             Ok(())
         }
-        Node::LinkReference(_) => Err(RenderError::NodeNotSupported {
-            node_type: "Reference Style Links",
-        }),
         Node::MdxjsEsm(_)
         | Node::MdxFlowExpression(_)
         | Node::MdxJsxFlowElement(_)
         | Node::MdxJsxTextElement(_)
         | Node::MdxTextExpression(_) => Err(RenderError::NodeNotSupported { node_type: "JSX" }),
+    }
+}
 
-        // TODO:
-        Node::Math(_) | Node::InlineMath(_) => {
-            Err(RenderError::NodeNotSupported { node_type: "Maths" })
-        }
-        Node::Definition(_) => Err(RenderError::NodeNotSupported {
-            node_type: "Definitions",
-        }),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use markdown::to_mdast;
+
+    fn render_with(md: &str, config: &MarkdownConfig) -> Result<String, RenderError> {
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let ast = to_mdast(md, &parse_options()).unwrap();
+        let ctx = RenderContext::new(&ast, &syntax_set, config, SlugMode::Ascii);
+        let mut builder = vec![];
+        mdast_into_str_builder(&ast, &mut builder, &ctx)?;
+        footnotes_into_str_builder(&mut builder, &ctx)?;
+        Ok(builder.concat())
+    }
+
+    fn render(md: &str) -> Result<String, RenderError> {
+        render_with(md, &MarkdownConfig::default())
+    }
+
+    #[test]
+    fn test_reference_links() {
+        assert_eq!(
+            render("[text][Ref]\n\n[REF]: /url \"Title\"").unwrap(),
+            "<p><a href=\"/url\" title=\"Title\">text</a></p>"
+        );
+    }
+
+    #[test]
+    fn test_images() {
+        assert_eq!(
+            render("![A cat](/cat.png \"My <cat>\")").unwrap(),
+            "<figure><img src=\"/cat.png\" alt=\"A cat\" /><figcaption>My &lt;cat&gt;</figcaption></figure>"
+        );
+        assert_eq!(
+            render("See ![A cat](/cat.png \"Cat\") here").unwrap(),
+            "<p>See <img src=\"/cat.png\" alt=\"A cat\" title=\"Cat\" /> here</p>"
+        );
+        assert_eq!(
+            render("![A cat](/cat.png)").unwrap(),
+            "<p><img src=\"/cat.png\" alt=\"A cat\" /></p>"
+        );
+        assert_eq!(
+            render("![A cat][pic]\n\n[pic]: /cat.png \"Cat\"").unwrap(),
+            "<figure><img src=\"/cat.png\" alt=\"A cat\" /><figcaption>Cat</figcaption></figure>"
+        );
+        assert_eq!(
+            render("A ![cat][pic] and ![dog][].\n\n[pic]: /cat.png\n[dog]: /dog.png").unwrap(),
+            "<p>A <img src=\"/cat.png\" alt=\"cat\" /> and <img src=\"/dog.png\" alt=\"dog\" />.</p>"
+        );
+    }
+
+    #[test]
+    fn test_undefined_reference() {
+        let ast = Node::LinkReference(LinkReference {
+            children: vec![],
+            position: None,
+            reference_kind: ReferenceKind::Full,
+            identifier: "missing".to_owned(),
+            label: Some("Missing".to_owned()),
+        });
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let config = MarkdownConfig::default();
+        let ctx = RenderContext::new(&ast, &syntax_set, &config, SlugMode::Ascii);
+        let err = mdast_into_str_builder(&ast, &mut vec![], &ctx).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No definition found for reference [Missing]"
+        );
+    }
 
-        Node::Image(_) | Node::ImageReference(_) => Err(RenderError::NodeNotSupported {
-            node_type: "Images",
-        }),
+    #[test]
+    fn test_undefined_reference_text() {
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let config = MarkdownConfig::default();
+        let md = "See [x][missing], [*y*][Other  Label], [collapsed][] and [z][ok].\n\n\
+                  [a] and [b] [c] aren't references, nor are m[i][j] and a[0][1][k].\n\n\
+                  [ok]: /url";
+        let ast = to_mdast(md, &parse_options()).unwrap();
+        let ctx = RenderContext::new(&ast, &syntax_set, &config, SlugMode::Ascii);
+        let mut builder = vec![];
+        mdast_into_str_builder(&ast, &mut builder, &ctx).unwrap();
+        assert!(builder
+            .concat()
+            .starts_with("<p>See [x][missing], [<em>y</em>]"));
+        assert_eq!(
+            ctx.undefined_references(),
+            ["[missing]", "[Other  Label]", "[collapsed]"]
+        );
+    }
+
+    #[test]
+    fn test_math() {
+        assert_eq!(render("$x$").unwrap(), "<p><math><mi>x</mi></math></p>");
+        let katex = MarkdownConfig {
+            math: MathMode::Katex,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_with("$$\na<b\n$$", &katex).unwrap(),
+            "<div class=\"math math-display\">\\[a&lt;b\\]</div>"
+        );
+    }
+
+    #[test]
+    fn test_invalid_math() {
+        let err = render("# Title\n\n$$\nx + 1\n\\frac{1}{2\n$$").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid maths on line 5: Unclosed `{`\n    \\frac{1}{2\n            ^"
+        );
+    }
+
+    #[test]
+    fn test_raw_html() {
+        let md = "<div onclick=\"x()\">\n\nHi <b>there</b>\n\n</div>";
+        assert_eq!(
+            render(md).unwrap(),
+            "<div onclick=\"x()\"><p>Hi <b>there</b></p></div>"
+        );
+
+        let config = MarkdownConfig {
+            raw_html: RawHtml::Sanitise,
+            ..Default::default()
+        };
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let ast = to_mdast(md, &parse_options()).unwrap();
+        let ctx = RenderContext::new(&ast, &syntax_set, &config, SlugMode::Ascii);
+        let mut builder = vec![];
+        mdast_into_str_builder(&ast, &mut builder, &ctx).unwrap();
+        assert_eq!(builder.concat(), "<div><p>Hi <b>there</b></p></div>");
+        assert_eq!(ctx.removed_html(), ["`onclick` attribute on <div>"]);
+
+        let inline = "See *this* <script>alert(*1*)</script> here.\n\nOpen <script>x\n\nAfter.";
+        assert_eq!(
+            render_with(inline, &config).unwrap(),
+            "<p>See <em>this</em>  here.</p><p>Open </p><p>After.</p>"
+        );
+        let strip = MarkdownConfig {
+            raw_html: RawHtml::Strip,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_with("a <script>`b`</script> c", &strip).unwrap(),
+            "<p>a  c</p>"
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(render("- a\n- b").unwrap(), "<ul><li>a</li><li>b</li></ul>");
+        assert_eq!(
+            render("3. a\n\n4. b").unwrap(),
+            "<ol start=\"3\"><li><p>a</p></li><li><p>b</p></li></ol>"
+        );
+        assert_eq!(
+            render("1. a\n2. b").unwrap(),
+            "<ol><li>a</li><li>b</li></ol>"
+        );
+        assert_eq!(
+            render("- [ ] todo\n- [x] done").unwrap(),
+            "<ul><li class=\"task-list-item\"><input type=\"checkbox\" disabled=\"\" /> todo</li>\
+             <li class=\"task-list-item\"><input type=\"checkbox\" disabled=\"\" checked=\"\" /> done</li></ul>"
+        );
+    }
+
+    #[test]
+    fn test_footnotes() {
+        let md = "A[^b] c[^a] d[^B].\n\n[^a]: First.\n\n[^b]: Second[^a].\n\n[^unused]: Unused.";
+        assert_eq!(
+            render(md).unwrap(),
+            "<p>A<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup> \
+             c<sup class=\"footnote-ref\"><a href=\"#fn-2\" id=\"fnref-2\">2</a></sup> \
+             d<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1-2\">1</a></sup>.</p>\
+             <section class=\"footnotes\"><ol>\
+             <li id=\"fn-1\"><p>Second<sup class=\"footnote-ref\"><a href=\"#fn-2\" id=\"fnref-2-2\">2</a></sup>.\
+             \x20<a href=\"#fnref-1\" class=\"footnote-backref\">↩</a>\
+             \x20<a href=\"#fnref-1-2\" class=\"footnote-backref\">↩<sup>2</sup></a></p></li>\
+             <li id=\"fn-2\"><p>First.\
+             \x20<a href=\"#fnref-2\" class=\"footnote-backref\">↩</a>\
+             \x20<a href=\"#fnref-2-2\" class=\"footnote-backref\">↩<sup>2</sup></a></p></li>\
+             </ol></section>"
+        );
+    }
+
+    #[test]
+    fn test_heading_ids() {
+        let md = "# example\n\n## example\n\n### example {#custom}\n\n## example-1\n\n## custom";
+        assert_eq!(
+            render(md).unwrap(),
+            "<h1 id=\"example\">example</h1><h2 id=\"example-1\">example</h2>\
+             <h3 id=\"custom\">example</h3><h2 id=\"example-1-1\">example-1</h2>\
+             <h2 id=\"custom-1\">custom</h2>"
+        );
+
+        let ast = to_mdast(md, &parse_options()).unwrap();
+        let Node::Root(root) = &ast else {
+            unreachable!()
+        };
+        let toc = Toc::from_mdast(root, None, SlugMode::Ascii).unwrap();
+        assert_eq!(
+            toc.to_html().unwrap(),
+            "<ol><li><a href=\"#example-1\">example</a></li><ol>\
+             <li><a href=\"#custom\">example</a></li></ol>\
+             <li><a href=\"#example-1-1\">example-1</a></li>\
+             <li><a href=\"#custom-1\">custom</a></li></ol>"
+        );
+
+        let md = "## A {#x}\n\n## B {#x}\n\n## fn 1\n\n## C {#fnref-1}\n\nText[^1]\n\n[^1]: Note";
+        let html = render(md).unwrap();
+        assert!(html.starts_with(
+            "<h2 id=\"x\">A</h2><h2 id=\"x-1\">B</h2>\
+             <h2 id=\"section-fn-1\">fn 1</h2><h2 id=\"section-fnref-1\">C</h2>"
+        ));
+        // Ids follow headings that have been cloned, and built ones get one
+        let ast = to_mdast("# T\n\n## A\n\n## A", &parse_options()).unwrap();
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let config = MarkdownConfig::default();
+        let ctx = RenderContext::new(&ast, &syntax_set, &config, SlugMode::Ascii);
+        let cloned = ast.children().unwrap()[2].clone();
+        let built = Node::Heading(Heading {
+            children: vec![Node::Text(Text {
+                value: "Built".to_owned(),
+                position: None,
+            })],
+            position: None,
+            depth: 2,
+        });
+        let mut builder = vec![];
+        mdast_into_str_builder(&cloned, &mut builder, &ctx).unwrap();
+        mdast_into_str_builder(&built, &mut builder, &ctx).unwrap();
+        assert_eq!(
+            builder.concat(),
+            "<h2 id=\"a-1\">A</h2><h2 id=\"built\">Built</h2>"
+        );
+
+        assert!(!is_footnote_id("fn-1-1"));
+        assert!(is_footnote_id("fnref-2-3"));
+    }
+
+    #[test]
+    fn test_toc_config() {
+        let md = "# T\n\n## A\n\n### A1\n\n#### A1a\n\n## B\n\n### B1";
+        let ast = to_mdast(md, &parse_options()).unwrap();
+        let Node::Root(root) = &ast else {
+            unreachable!()
+        };
+        let config = TocConfig {
+            min_depth: 3,
+            max_depth: 3,
+            numbered: true,
+        };
+        let toc = Toc::from_mdast(root, None, SlugMode::Ascii)
+            .unwrap()
+            .configure(&config);
+        let entries: Vec<_> = toc
+            .children
+            .iter()
+            .map(|entry| {
+                (
+                    entry.name.as_str(),
+                    entry.number.as_deref(),
+                    entry.children.len(),
+                )
+            })
+            .collect();
+        assert_eq!(entries, [("A1", Some("1"), 0), ("B1", Some("2"), 0)]);
+        assert_eq!(
+            toc.to_html().unwrap(),
+            "<ol><li><a href=\"#a1\"><span class=\"toc-number\">1</span> A1</a></li>\
+             <li><a href=\"#b1\"><span class=\"toc-number\">2</span> B1</a></li></ol>"
+        );
+    }
+
+    #[test]
+    fn test_escaping() {
+        let cases = [
+            ("a < b & c", "<p>a &lt; b &amp; c</p>"),
+            ("`<script>`", "<p><code>&lt;script&gt;</code></p>"),
+            (
+                "[a](/x \"say \\\"hi\\\" & 'bye'\")",
+                "<p><a href=\"/x\" title=\"say &quot;hi&quot; &amp; &#39;bye&#39;\">a</a></p>",
+            ),
+            (
+                "[a](/search?q=1&lang=en)",
+                "<p><a href=\"/search?q=1&amp;lang=en\">a</a></p>",
+            ),
+            ("[a](javascript:alert(1))", "<p><a href=\"\">a</a></p>"),
+            ("[a](JavaScript:alert(1))", "<p><a href=\"\">a</a></p>"),
+            (
+                "![\"x\"](javascript:alert(1))",
+                "<p><img src=\"\" alt=\"&quot;x&quot;\" /></p>",
+            ),
+            (
+                "[a](mailto:me@example.com)",
+                "<p><a href=\"mailto:me@example.com\">a</a></p>",
+            ),
+            (
+                "[a](/posts/a:b.html)",
+                "<p><a href=\"/posts/a:b.html\">a</a></p>",
+            ),
+        ];
+        for (md, html) in cases {
+            assert_eq!(render(md).unwrap(), html, "rendering {md:?}");
+        }
+        assert!(render("```\n<b> & \"\n```")
+            .unwrap()
+            .contains("&lt;b&gt; &amp; &quot;"));
     }
 }
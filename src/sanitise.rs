@@ -0,0 +1,377 @@
+use serde::Deserialize;
+
+use crate::utils::is_safe_url;
+
+/// What happens to raw HTML written in a post.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RawHtml {
+    /// Pass it through untouched
+    #[default]
+    Allow,
+    /// Remove every tag, keeping the text between them
+    Strip,
+    /// Keep only the tags and attributes in the allow-list
+    #[serde(alias = "sanitize")]
+    Sanitise,
+}
+
+/// Tags kept by [`RawHtml::Sanitise`].
+#[rustfmt::skip]
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "blockquote", "br", "caption", "cite", "code", "col",
+    "colgroup", "dd", "del", "details", "dfn", "div", "dl", "dt", "em", "figcaption", "figure",
+    "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "img", "ins", "kbd", "li", "mark", "ol", "p",
+    "pre", "q", "rp", "rt", "ruby", "s", "samp", "small", "span", "strong", "sub", "summary",
+    "sup", "table", "tbody", "td", "tfoot", "th", "thead", "time", "tr", "u", "ul", "var", "wbr",
+];
+
+/// Tags whose contents are removed along with them, rather than being left
+/// behind as text.
+const DROPPED_WITH_CONTENTS: &[&str] = &["script", "style"];
+
+/// Attributes kept on any allowed tag.
+const GLOBAL_ATTRIBUTES: &[&str] = &["class", "dir", "id", "lang", "title"];
+
+/// Attributes kept on particular allowed tags.
+const TAG_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("a", &["href"]),
+    ("blockquote", &["cite"]),
+    ("col", &["span"]),
+    ("colgroup", &["span"]),
+    ("del", &["cite", "datetime"]),
+    ("details", &["open"]),
+    ("img", &["alt", "height", "src", "width"]),
+    ("ins", &["cite", "datetime"]),
+    ("li", &["value"]),
+    ("ol", &["reversed", "start", "type"]),
+    ("q", &["cite"]),
+    ("td", &["colspan", "rowspan"]),
+    ("th", &["colspan", "rowspan", "scope"]),
+    ("time", &["datetime"]),
+];
+
+/// Attributes holding a URL, which are also checked for unsafe schemes.
+const URL_ATTRIBUTES: &[&str] = &["cite", "href", "src"];
+
+fn attribute_allowed(tag: &str, attribute: &str) -> bool {
+    GLOBAL_ATTRIBUTES.contains(&attribute)
+        || TAG_ATTRIBUTES
+            .iter()
+            .any(|(name, attributes)| *name == tag && attributes.contains(&attribute))
+}
+
+struct Attribute<'a> {
+    name: String,
+    value: Option<&'a str>,
+}
+
+struct Tag<'a> {
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    attributes: Vec<Attribute<'a>>,
+}
+
+/// Parses the tag at the start of `html`, which begins with `<`, returning
+/// it and the length of its source. `None` if `html` doesn't start with a
+/// complete tag.
+fn parse_tag(html: &str) -> Option<(Tag<'_>, usize)> {
+    let is_name_end = |c: char| c.is_whitespace() || c == '/' || c == '>';
+
+    let mut rest = html.strip_prefix('<')?;
+    let closing = rest.starts_with('/');
+    if closing {
+        rest = &rest[1..];
+    }
+    if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let name_end = rest.find(is_name_end)?;
+    let name = rest[..name_end].to_ascii_lowercase();
+    rest = &rest[name_end..];
+
+    let mut attributes = vec![];
+    let mut self_closing = false;
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('>') {
+            rest = after;
+            break;
+        }
+        if let Some(after) = rest.strip_prefix('/') {
+            self_closing = true;
+            rest = after;
+            continue;
+        }
+        if rest.is_empty() {
+            return None;
+        }
+
+        let name_end = rest
+            .find(|c: char| is_name_end(c) || c == '=')
+            .unwrap_or(rest.len())
+            .max(1);
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = None;
+        if let Some(after) = rest.strip_prefix('=') {
+            rest = after.trim_start();
+            let (text, len) = match rest.chars().next()? {
+                quote @ ('"' | '\'') => {
+                    let end = rest[1..].find(quote)?;
+                    (&rest[1..end + 1], end + 2)
+                }
+                _ => {
+                    let end = rest
+                        .find(|c: char| c.is_whitespace() || c == '>')
+                        .unwrap_or(rest.len());
+                    (&rest[..end], end)
+                }
+            };
+            value = Some(text);
+            rest = &rest[len..];
+        }
+        attributes.push(Attribute { name, value });
+    }
+
+    let tag = Tag {
+        name,
+        closing,
+        self_closing,
+        attributes,
+    };
+    Some((tag, html.len() - rest.len()))
+}
+
+/// Decodes the character references that could hide a URL's scheme, e.g.
+/// `javascript&#58;`. Others are left as they are. `None` if a numeric
+/// reference doesn't end in `;`, which browsers still decode but in ways
+/// that depend on what follows, so the URL can't be trusted.
+fn decode_references(value: &str) -> Option<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(numeric) = rest.strip_prefix("&#") {
+            let (digits, radix) = match numeric.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16),
+                None => (numeric, 10),
+            };
+            let end = digits
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(digits.len());
+            if end > 0 {
+                let c = u32::from_str_radix(&digits[..end], radix)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                out.push(c);
+                rest = digits[end..].strip_prefix(';')?;
+                continue;
+            }
+        }
+        let named = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "colon" => Some(':'),
+                "Tab" => Some('\t'),
+                "NewLine" => Some('\n'),
+                "amp" => Some('&'),
+                _ => None,
+            };
+            c.map(|c| (c, end + 1))
+        });
+        match named {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Notes something taken out of a post, once.
+fn record(removed: &mut Vec<String>, what: String) {
+    if !removed.contains(&what) {
+        removed.push(what);
+    }
+}
+
+fn write_tag(out: &mut String, tag: &Tag, removed: &mut Vec<String>) {
+    out.push('<');
+    if tag.closing {
+        out.push('/');
+    }
+    out.push_str(&tag.name);
+    for Attribute { name, value } in &tag.attributes {
+        if !attribute_allowed(&tag.name, name) {
+            record(removed, format!("`{name}` attribute on <{}>", tag.name));
+            continue;
+        }
+        if URL_ATTRIBUTES.contains(&name.as_str())
+            && !value
+                .and_then(decode_references)
+                .is_some_and(|value| is_safe_url(&value))
+        {
+            record(removed, format!("unsafe `{name}` on <{}>", tag.name));
+            continue;
+        }
+        out.push(' ');
+        out.push_str(name);
+        if let Some(value) = value {
+            out.push_str("=\"");
+            out.push_str(&value.replace('"', "&quot;").replace('<', "&lt;"));
+            out.push('"');
+        }
+    }
+    if tag.self_closing {
+        out.push_str(" /");
+    }
+    out.push('>');
+}
+
+/// Skips past the closing `</name>` tag in `html`, if it has one.
+fn after_closing_tag<'a>(html: &'a str, name: &str) -> Option<&'a str> {
+    let end = html.to_ascii_lowercase().find(&format!("</{name}"))?;
+    let after = &html[end..];
+    after.find('>').map(|close_end| &after[close_end + 1..])
+}
+
+/// Applies `policy` to a fragment of raw HTML from a post, adding a
+/// description of anything taken out to `removed`.
+///
+/// Inline HTML comes in a fragment per tag, so `dropping` holds the name of a
+/// [`DROPPED_WITH_CONTENTS`] element that's still open when the fragment
+/// ends. Until a later fragment closes it, everything is left out.
+pub fn sanitise(
+    html: &str,
+    policy: RawHtml,
+    removed: &mut Vec<String>,
+    dropping: &mut Option<String>,
+) -> String {
+    if policy == RawHtml::Allow {
+        return html.to_owned();
+    }
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    if let Some(name) = dropping.as_deref() {
+        match after_closing_tag(rest, name) {
+            Some(after) => {
+                rest = after;
+                *dropping = None;
+            }
+            None => return out,
+        }
+    }
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            record(removed, rest[..end].to_owned());
+            rest = &rest[end..];
+            continue;
+        }
+
+        let Some((tag, len)) = parse_tag(rest) else {
+            out.push_str("&lt;");
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[len..];
+
+        if policy == RawHtml::Sanitise && ALLOWED_TAGS.contains(&tag.name.as_str()) {
+            write_tag(&mut out, &tag, removed);
+            continue;
+        }
+        record(removed, format!("<{}>", tag.name));
+        if !tag.closing && DROPPED_WITH_CONTENTS.contains(&tag.name.as_str()) {
+            match after_closing_tag(rest, &tag.name) {
+                Some(after) => rest = after,
+                None => {
+                    *dropping = Some(tag.name);
+                    return out;
+                }
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitised(html: &str, policy: RawHtml) -> (String, Vec<String>) {
+        let mut removed = vec![];
+        (sanitise(html, policy, &mut removed, &mut None), removed)
+    }
+
+    #[test]
+    fn test_sanitise() {
+        let (html, removed) = sanitised(
+            "<div class=\"note\" onclick='steal()'><a href=\"java&#x09;script&#58;x\">a</a>\
+             <img src=/cat.png alt=\"A cat\"><script>alert(1)</script><iframe src=\"x\"></iframe>\
+             <!-- hidden --></div>",
+            RawHtml::Sanitise,
+        );
+        assert_eq!(
+            html,
+            "<div class=\"note\"><a>a</a><img src=\"/cat.png\" alt=\"A cat\"></div>"
+        );
+        assert_eq!(
+            removed,
+            [
+                "`onclick` attribute on <div>",
+                "unsafe `href` on <a>",
+                "<script>",
+                "<iframe>"
+            ]
+        );
+
+        // Numeric references don't need their `;`
+        for payload in [
+            "javascript&#58alert(1)",
+            "javascript&#x3Aalert(1)",
+            "jav&#X61script&#0058;x",
+        ] {
+            let (html, removed) = sanitised(
+                &format!("<a href=\"{payload}\">click</a>"),
+                RawHtml::Sanitise,
+            );
+            assert_eq!(html, "<a>click</a>");
+            assert_eq!(removed, ["unsafe `href` on <a>"]);
+        }
+        assert_eq!(
+            decode_references("a&#38;b&amp;c&#xZ&").as_deref(),
+            Some("a&b&c&#xZ&")
+        );
+        assert_eq!(decode_references("/a?b=1&#38c=2"), None);
+    }
+
+    #[test]
+    fn test_strip() {
+        let (html, removed) = sanitised("<p>a < b <em>c</em></p>", RawHtml::Strip);
+        assert_eq!(html, "a &lt; b c");
+        assert_eq!(removed, ["<p>", "<em>"]);
+        assert_eq!(
+            sanitised("<b onclick=x>", RawHtml::Allow).0,
+            "<b onclick=x>"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::{error, warn};
+use syntect::parsing::SyntaxSet;
+
+use crate::config::Config;
+use crate::index::Index;
+use crate::post::{Post, Tag};
+use crate::utils::files_within;
+
+/// Every post on the site, parsed once and shared by the post pages, the
+/// listings, the feeds and the sitemap.
+pub struct Site {
+    posts: Vec<Post>,
+}
+
+impl Site {
+    /// Holds `posts` newest first. Undated posts go last.
+    pub fn new(mut posts: Vec<Post>) -> Site {
+        posts.sort_by_key(|post| std::cmp::Reverse(post.metadata.datetime()));
+        disambiguate_tags(&mut posts);
+        Site { posts }
+    }
+
+    /// Parses every post in `files`, leaving out (and logging) any that
+    /// can't be parsed.
+    pub fn from_file_list(
+        site_root: &Path,
+        files: &[PathBuf],
+        syntax_set: &SyntaxSet,
+        config: &Config,
+    ) -> Site {
+        let posts = files
+            .iter()
+            .filter_map(
+                |path| match Post::from_file(site_root, path, syntax_set, config) {
+                    Ok(post) => Some(post),
+                    Err(err) => {
+                        error!("Couldn't render page {}. {}", path.to_string_lossy(), err);
+                        None
+                    }
+                },
+            )
+            .collect();
+        Site::new(posts)
+    }
+
+    pub fn from_path(
+        site_root: &Path,
+        syntax_set: &SyntaxSet,
+        config: &Config,
+    ) -> Result<Site, std::io::Error> {
+        Ok(Site::from_file_list(
+            site_root,
+            &files_within(site_root, None)?,
+            syntax_set,
+            config,
+        ))
+    }
+
+    pub fn posts(&self) -> &[Post] {
+        &self.posts
+    }
+
+    /// Every listing page: the main index, the list of tags and each tag's
+    /// posts.
+    pub fn indexes(&self, config: &Config) -> Vec<Index> {
+        let posts = self.posts.iter().map(|post| post.metadata.clone());
+        Index::from_posts(posts.collect(), config)
+    }
+}
+
+/// Gives tags with different names but the same slug, such as `C++` and `C`,
+/// a slug of their own so they don't share a page. Names differing only in
+/// case are the same tag.
+fn disambiguate_tags(posts: &mut [Post]) {
+    // Lowercased tag names and their slugs, and the name each slug is for
+    let mut slugs: HashMap<String, String> = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+    for tag in posts.iter_mut().flat_map(|post| &mut post.metadata.tags) {
+        let slug = slugs.entry(tag.name.to_lowercase()).or_insert_with(|| {
+            let mut slug = tag.slug.clone();
+            let mut n = 0;
+            while names.contains_key(&slug) {
+                n += 1;
+                slug = format!("{}-{n}", tag.slug);
+            }
+            if n > 0 {
+                warn!(
+                    "Tags {:?} and {:?} have the same slug, so {:?} is served from {}{slug}/",
+                    names[&tag.slug],
+                    tag.name,
+                    tag.name,
+                    Tag::INDEX_PERMALINK
+                );
+            }
+            names.insert(slug.clone(), tag.name.clone());
+            slug
+        });
+        if *slug != tag.slug {
+            *tag = Tag::with_slug(&tag.name, slug.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::PostMeta;
+    use crate::utils::SlugMode;
+
+    #[test]
+    fn test_tag_slugs() {
+        let post = |tags: &[&str]| {
+            let tags = tags.iter().map(|tag| Tag::new(tag, SlugMode::Ascii));
+            let metadata = PostMeta {
+                tags: tags.collect(),
+                ..Default::default()
+            };
+            Post::with_content(metadata, "")
+        };
+        let site = Site::new(vec![post(&["C++", "C#"]), post(&["c++", "C", "!!!"])]);
+        let slugs: Vec<Vec<_>> = site
+            .posts()
+            .iter()
+            .map(|post| {
+                post.metadata
+                    .tags
+                    .iter()
+                    .map(|tag| &tag.permalink)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            slugs,
+            [
+                vec!["/tags/c/", "/tags/c-1/"],
+                vec!["/tags/c/", "/tags/c-2/", "/tags/tag/"]
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::index::Index;
+use crate::post::{Post, PostMeta};
+use crate::utils::escape_html;
+
+#[derive(Error, Debug)]
+pub enum SitemapError {
+    #[error("Sitemaps need absolute URLs. Set `base_url` under `[site]` in _config/site.toml")]
+    MissingBaseUrl,
+}
+
+/// When a post last changed: its date if that can be understood, otherwise
+/// the modification time of its source file.
+fn last_modified(post: &PostMeta) -> Option<String> {
+    post.datetime()
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .or_else(|| {
+            let modified: DateTime<Utc> = post.modified?.into();
+            Some(modified.format("%Y-%m-%d").to_string())
+        })
+}
+
+fn push_url(out: &mut String, loc: &str, lastmod: Option<String>) {
+    out.push_str("<url><loc>");
+    out.push_str(&escape_html(loc));
+    out.push_str("</loc>");
+    if let Some(lastmod) = lastmod {
+        out.push_str("<lastmod>");
+        out.push_str(&lastmod);
+        out.push_str("</lastmod>");
+    }
+    out.push_str("</url>\n");
+}
+
+/// Lists every index and tag page and every post, except drafts and posts
+/// marked `noindex`.
+pub fn sitemap(posts: &[Post], pages: &[Index], config: &Config) -> Result<String, SitemapError> {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+
+    for index in pages {
+        let loc = config
+            .absolute_url(index.permalink())
+            .ok_or(SitemapError::MissingBaseUrl)?;
+        let lastmod = index.posts().iter().filter_map(last_modified).max();
+        push_url(&mut out, &loc, lastmod);
+    }
+
+    for post in posts {
+        let metadata = &post.metadata;
+        if metadata.draft || metadata.noindex {
+            continue;
+        }
+        let loc = config
+            .absolute_url(&metadata.permalink)
+            .ok_or(SitemapError::MissingBaseUrl)?;
+        push_url(&mut out, &loc, last_modified(metadata));
+    }
+
+    out.push_str("</urlset>\n");
+    Ok(out)
+}
+
+pub fn robots_txt(config: &Config) -> Result<String, SitemapError> {
+    let sitemap = config
+        .absolute_url("/sitemap.xml")
+        .ok_or(SitemapError::MissingBaseUrl)?;
+    Ok(format!("User-agent: *\nAllow: /\n\nSitemap: {sitemap}\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(title: &str, draft: bool, noindex: bool) -> Post {
+        let metadata = PostMeta {
+            draft,
+            noindex,
+            ..PostMeta::dated(title, "2023-08-01")
+        };
+        Post::with_content(metadata, "")
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let mut config = Config::default();
+        config.site.base_url = Some("https://example.com".to_owned());
+        let posts = [
+            post("shown", false, false),
+            post("draft", true, false),
+            post("hidden", false, true),
+        ];
+
+        let sitemap = sitemap(&posts, &[], &config).unwrap();
+        assert!(sitemap.contains(
+            "<url><loc>https://example.com/shown.html</loc><lastmod>2023-08-01</lastmod></url>"
+        ));
+        assert!(!sitemap.contains("draft.html"));
+        assert!(!sitemap.contains("hidden.html"));
+        assert_eq!(
+            robots_txt(&config).unwrap(),
+            "User-agent: *\nAllow: /\n\nSitemap: https://example.com/sitemap.xml\n"
+        );
+    }
+}
@@ -1,72 +1,300 @@
 use std::{
     borrow::Cow,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
-pub fn parameterize(s: &str) -> Cow<str> {
-    let mut char_iter = s.chars().enumerate();
-    if let Some((i, _)) = char_iter.find(|(_i, x)| !x.is_alphanumeric()) {
-        let mut out = String::with_capacity(s.len());
-        out += &s[0..i];
-
-        while let Some((start, _)) = char_iter.find(|(_i, x)| x.is_alphanumeric()) {
-            out += "-";
-            if let Some((end, _)) = char_iter.find(|(_i, x)| !x.is_alphanumeric()) {
-                out += &s[start..end];
-            } else {
-                out += &s[start..];
-                break;
+
+use deunicode::deunicode;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+
+/// Which characters slugs may contain, set by `site.slugs`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlugMode {
+    /// Lowercase ASCII letters and digits, transliterating other scripts
+    /// (`Ünïcode` becomes `unicode`)
+    #[default]
+    Ascii,
+    /// Lowercase letters and digits from any script
+    Unicode,
+}
+
+/// Turns `s` into a slug for use in URLs and ids: lowercase letters and
+/// digits, with each run of anything else replaced by a single `-`.
+pub fn parameterize(s: &str, mode: SlugMode) -> Cow<'_, str> {
+    let is_slug_char = |c: char| match mode {
+        SlugMode::Ascii => c.is_ascii_lowercase() || c.is_ascii_digit(),
+        SlugMode::Unicode => c.is_alphanumeric() && !c.is_uppercase(),
+    };
+    let is_slug = !s.starts_with('-')
+        && !s.ends_with('-')
+        && !s.contains("--")
+        && s.chars().all(|c| c == '-' || is_slug_char(c));
+    if is_slug {
+        return Cow::Borrowed(s);
+    }
+
+    let text = match mode {
+        SlugMode::Ascii => Cow::Owned(deunicode(s)),
+        SlugMode::Unicode => Cow::Borrowed(s),
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut gap = false;
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if is_slug_char(c) {
+            if gap && !out.is_empty() {
+                out.push('-');
             }
+            gap = false;
+            out.push(c);
+        } else {
+            gap = true;
         }
+    }
+    Cow::Owned(out)
+}
+
+/// Escapes text for use in HTML or XML, in both element content and quoted
+/// attribute values.
+pub fn escape_html(s: &str) -> Cow<'_, str> {
+    if !s.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// URL schemes links and images may use. URLs without a scheme are
+/// relative and always allowed.
+const SAFE_URL_SCHEMES: [&str; 7] = ["http", "https", "mailto", "tel", "ftp", "irc", "xmpp"];
+
+/// Whether a URL can be put in a page without risk of it running script,
+/// as a `javascript:` URL would.
+pub fn is_safe_url(url: &str) -> bool {
+    // Browsers ignore tabs and newlines anywhere in a URL, and leading spaces
+    // and control characters, so `java\tscript:` is still a `javascript:` URL.
+    let url: String = url
+        .trim_start_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    let scheme = url
+        .find([':', '/', '?', '#'])
+        .filter(|&end| url[end..].starts_with(':'))
+        .map(|end| &url[..end]);
+    match scheme {
+        Some(scheme) => SAFE_URL_SCHEMES
+            .iter()
+            .any(|safe| safe.eq_ignore_ascii_case(scheme)),
+        None => true,
+    }
+}
 
-        Cow::Owned(out)
+/// Where the page served from `permalink` is written, relative to the build
+/// directory. Permalinks ending in `/` are written to an `index.html`.
+pub fn permalink_to_path(permalink: &str) -> PathBuf {
+    let path = permalink.trim_start_matches('/');
+    if path.is_empty() || path.ends_with('/') {
+        PathBuf::from(path).join("index.html")
     } else {
-        Cow::Borrowed(s)
+        PathBuf::from(path)
     }
 }
 
-pub fn files_within(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
-    let mut acc = vec![];
-
-    fn inner(root: &Path, branch: &Path, acc: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
-        for entry in std::fs::read_dir(root.join(branch))? {
-            let entry = entry?;
-            let metadata = entry.metadata()?;
-            let file_name = entry.file_name();
-            let path = PathBuf::from(&file_name);
-
-            if metadata.is_file()
-                && path.extension().and_then(std::ffi::OsStr::to_str) == Some("md")
-            {
-                acc.push(branch.join(file_name));
-            } else if metadata.is_dir() {
-                inner(root, &branch.join(file_name), acc)?;
+/// The file listing paths, relative to the site root, that discovery skips
+pub const IGNORE_FILE: &str = ".rhodiumignore";
+
+/// Decides which files under the site root are part of the site. Skipped are
+/// directories starting with `_` or `.` (such as `_config` and `.git`), the
+/// build directory, and anything matching a pattern in [`IGNORE_FILE`].
+struct Discovery<'a> {
+    site_root: &'a Path,
+    build_dir: Option<PathBuf>,
+    ignore: Gitignore,
+}
+
+impl<'a> Discovery<'a> {
+    fn new(site_root: &'a Path, build_dir: Option<&Path>) -> Result<Self, std::io::Error> {
+        let mut builder = GitignoreBuilder::new(site_root);
+        let ignore_file = site_root.join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            if let Some(err) = builder.add(&ignore_file) {
+                return Err(invalid_ignore_file(err));
             }
         }
-        Ok(())
+        Ok(Discovery {
+            site_root,
+            build_dir: build_dir.and_then(|dir| dir.canonicalize().ok()),
+            ignore: builder.build().map_err(invalid_ignore_file)?,
+        })
+    }
+
+    /// Whether `path`, relative to the site root, is left out of the site.
+    fn skips(&self, path: &Path, is_dir: bool) -> bool {
+        let mut dirs = path.ancestors().skip(if is_dir { 0 } else { 1 });
+        path == Path::new(IGNORE_FILE)
+            || dirs.any(|dir| {
+                dir.file_name()
+                    .and_then(std::ffi::OsStr::to_str)
+                    .is_some_and(|name| name.starts_with(['_', '.']))
+                    || self.build_dir.is_some()
+                        && self.site_root.join(dir).canonicalize().ok() == self.build_dir
+            })
+            || self
+                .ignore
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
     }
 
-    inner(path, &PathBuf::new(), &mut acc)?;
-    Ok(acc)
+    fn walk(&self, include: &dyn Fn(&Path) -> bool) -> Result<Vec<PathBuf>, std::io::Error> {
+        let mut acc = vec![];
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(branch) = dirs.pop() {
+            for entry in std::fs::read_dir(self.site_root.join(&branch))? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let path = branch.join(entry.file_name());
+
+                if metadata.is_file() && include(&path) && !self.skips(&path, false) {
+                    acc.push(path);
+                } else if metadata.is_dir() && !self.skips(&path, true) {
+                    dirs.push(path);
+                }
+            }
+        }
+        acc.sort();
+        Ok(acc)
+    }
+}
+
+fn invalid_ignore_file(err: ignore::Error) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid {IGNORE_FILE}. {err}"),
+    )
+}
+
+fn is_markdown(path: &Path) -> bool {
+    path.extension().and_then(std::ffi::OsStr::to_str) == Some("md")
+}
+
+/// Every Markdown file that's part of the site under `path`, relative to it.
+/// `build_dir` is skipped if it's inside `path`.
+pub fn files_within(path: &Path, build_dir: Option<&Path>) -> Result<Vec<PathBuf>, std::io::Error> {
+    Discovery::new(path, build_dir)?.walk(&is_markdown)
+}
+
+/// Every file under `path` that should be copied into the built site as-is:
+/// anything that isn't Markdown and isn't skipped by discovery.
+pub fn assets_within(path: &Path, build_dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    Discovery::new(path, Some(build_dir))?.walk(&|file| !is_markdown(file))
+}
+
+/// Whether `path`, relative to `site_root`, is a file [`files_within`] or
+/// [`assets_within`] would find.
+fn is_discovered(site_root: &Path, path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+        && Discovery::new(site_root, None).is_ok_and(|discovery| !discovery.skips(path, false))
+}
+
+/// Whether `path` is a post that [`files_within`] would find, so the
+/// development server can serve it.
+pub fn is_content(site_root: &Path, path: &Path) -> bool {
+    is_markdown(path) && is_discovered(site_root, path)
+}
+
+/// Whether `uri_path` names an asset that [`assets_within`] would copy, so the
+/// development server can serve it directly.
+pub fn is_asset(site_root: &Path, uri_path: &Path) -> bool {
+    !is_markdown(uri_path) && is_discovered(site_root, uri_path)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("plain"), Cow::Borrowed("plain"));
+        assert_eq!(
+            escape_html("<a href=\"x\">'&'</a>"),
+            Cow::<str>::Owned("&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;".into())
+        );
+    }
+
+    #[test]
+    fn test_permalink_to_path() {
+        assert_eq!(permalink_to_path("/"), PathBuf::from("index.html"));
+        assert_eq!(
+            permalink_to_path("/tags/rust/page/2/"),
+            PathBuf::from("tags/rust/page/2/index.html")
+        );
+        assert_eq!(
+            permalink_to_path("/posts/a.html"),
+            PathBuf::from("posts/a.html")
+        );
+    }
+
     #[test]
     fn test_parameterize() {
-        assert_eq!(parameterize("foo"), Cow::Borrowed("foo"));
+        let ascii = SlugMode::Ascii;
+        assert_eq!(parameterize("foo", ascii), Cow::Borrowed("foo"));
         assert_eq!(
-            parameterize("foo!!bar"),
+            parameterize("foo!!bar", ascii),
             Cow::<str>::Owned("foo-bar".into())
         );
         assert_eq!(
-            parameterize("foo!!bar!baz"),
+            parameterize("foo!!bar!baz", ascii),
             Cow::<str>::Owned("foo-bar-baz".into())
         );
         assert_eq!(
-            parameterize("foo!!bar!baz:"),
+            parameterize("foo!!bar!baz:", ascii),
             Cow::<str>::Owned("foo-bar-baz".into())
         );
+        assert_eq!(parameterize("Ünïcode Héading", ascii), "unicode-heading");
+        assert_eq!(parameterize("¿Qué tal?", ascii), "que-tal");
+        assert_eq!(
+            parameterize("Ünïcode Héading", SlugMode::Unicode),
+            "ünïcode-héading"
+        );
+        assert_eq!(
+            parameterize("日本語 テキスト", SlugMode::Unicode),
+            "日本語-テキスト"
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        let site_root = Path::new("site");
+        let mut builder = GitignoreBuilder::new(site_root);
+        for line in ["README.md", "drafts/", "*.tmp", "!keep.tmp"] {
+            builder.add_line(None, line).unwrap();
+        }
+        let discovery = Discovery {
+            site_root,
+            build_dir: None,
+            ignore: builder.build().unwrap(),
+        };
+        let skips = |path: &str| discovery.skips(Path::new(path), false);
+        assert!(skips("README.md"));
+        assert!(skips("posts/README.md"));
+        assert!(skips("drafts/idea.md"));
+        assert!(skips("posts/x.tmp"));
+        assert!(!skips("posts/keep.tmp"));
+        assert!(skips("_config/layouts/post.md"));
+        assert!(skips("posts/.git/HEAD"));
+        assert!(skips(IGNORE_FILE));
+        assert!(!skips("posts/hello.md"));
+        assert!(!skips("posts/_hello.md"));
     }
 }